@@ -0,0 +1,32 @@
+//! Cost of logging a record that no session is listening for.
+//!
+//! Numbers from `cargo bench --bench log_record` on a Linux x86_64 VM with one vCPU
+//! (Intel Xeon, rustc 1.95.0), where user_events cannot be registered. Before is the tree
+//! just before providers were cached per callsite, with this benchmark added to it.
+//!
+//! | Benchmark       | Before   | After    |
+//! |-----------------|----------|----------|
+//! | `info_disabled` | 165.6 ns | 155.1 ns |
+//! | `log_enabled`   |  93.3 ns |  54.4 ns |
+//!
+//! Reading the clock takes about 63 ns of `info_disabled` on this VM, before and after.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use log::{info, log_enabled, Level};
+
+fn log_record(c: &mut Criterion) {
+    log_etw::logger::new_logger("LogEtwBenchDefault").install();
+
+    // No session is listening, so these measure the cost of resolving the provider
+    // and checking whether the event is enabled.
+    c.bench_function("info_disabled", |b| {
+        b.iter(|| info!(target: "LogEtwBench", "Message {}", 42))
+    });
+
+    c.bench_function("log_enabled", |b| {
+        b.iter(|| log_enabled!(target: "LogEtwBench", Level::Info))
+    });
+}
+
+criterion_group!(benches, log_record);
+criterion_main!(benches);
@@ -0,0 +1,301 @@
+//! Derive macros for the `log-etw` crate, and the helpers behind its static event macros.
+//! Use them through `log-etw` rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitInt, LitStr};
+
+/// Implement `log_etw::typed_events::LogEtwEvent` for a struct with named fields.
+///
+/// ```ignore
+/// #[derive(LogEtwEvent)]
+/// #[etw(provider = "MyCompany_MyService", name = "RequestCompleted", level = "info", keyword = 0x2)]
+/// struct RequestCompleted<'a> {
+///     path: &'a str,
+///     #[etw(format = "hex")]
+///     status: u32,
+///     #[etw(rename = "LatencyMs")]
+///     latency_ms: f64,
+///     #[etw(skip)]
+///     retries: Vec<String>,
+/// }
+/// ```
+///
+/// Struct attributes:
+/// - `provider`: required. The provider name.
+/// - `name`: the event name. Defaults to the struct name.
+/// - `level`: `error`, `warn`, `info`, `debug` or `trace`. Defaults to `info`.
+/// - `keyword`: defaults to 1.
+/// - `opcode`: `info`, `start`, `stop` or an integer. Defaults to `info`.
+///
+/// Field attributes:
+/// - `rename`: the field name in the event. Defaults to the Rust field name.
+/// - `format`: `default`, `hex`, `pid`, `json` or `xml`.
+/// - `skip`: leave the field out of the event.
+#[proc_macro_derive(LogEtwEvent, attributes(etw))]
+pub fn derive_log_etw_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut provider: Option<LitStr> = None;
+    let mut name = LitStr::new(&input.ident.to_string(), input.ident.span());
+    let mut level = quote!(Info);
+    let mut keyword = quote!(1);
+    let mut opcode = quote!(0);
+
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("etw")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("provider") {
+                provider = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("name") {
+                name = meta.value()?.parse()?;
+            } else if meta.path.is_ident("level") {
+                let value: LitStr = meta.value()?.parse()?;
+                level = match value.value().to_ascii_lowercase().as_str() {
+                    "error" => quote!(Error),
+                    "warn" => quote!(Warn),
+                    "info" => quote!(Info),
+                    "debug" => quote!(Debug),
+                    "trace" => quote!(Trace),
+                    _ => {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "expected one of error, warn, info, debug or trace",
+                        ))
+                    }
+                };
+            } else if meta.path.is_ident("keyword") {
+                let value: LitInt = meta.value()?.parse()?;
+                keyword = quote!(#value);
+            } else if meta.path.is_ident("opcode") {
+                let value: syn::Lit = meta.value()?.parse()?;
+                opcode = match value {
+                    syn::Lit::Int(value) => quote!(#value),
+                    syn::Lit::Str(value) => match value.value().to_ascii_lowercase().as_str() {
+                        "info" => quote!(0),
+                        "start" => quote!(1),
+                        "stop" => quote!(2),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.span(),
+                                "expected one of info, start, stop or an integer",
+                            ))
+                        }
+                    },
+                    _ => {
+                        return Err(syn::Error::new(
+                            value.span(),
+                            "expected a string or integer",
+                        ))
+                    }
+                };
+            } else {
+                return Err(meta.error("unsupported etw attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let provider = provider.ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "missing #[etw(provider = \"...\")] attribute",
+        )
+    })?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            Fields::Unit => {
+                return expand_impl(&input, provider, name, level, keyword, opcode, Vec::new())
+            }
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "LogEtwEvent requires a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "LogEtwEvent can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut adds = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let mut field_name = LitStr::new(&ident.to_string(), ident.span());
+        let mut format = quote!(Default);
+        let mut skip = false;
+
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("etw")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    field_name = meta.value()?.parse()?;
+                } else if meta.path.is_ident("skip") {
+                    skip = true;
+                } else if meta.path.is_ident("format") {
+                    let value: LitStr = meta.value()?.parse()?;
+                    format = match value.value().to_ascii_lowercase().as_str() {
+                        "default" => quote!(Default),
+                        "hex" => quote!(Hex),
+                        "pid" => quote!(Pid),
+                        "json" => quote!(Json),
+                        "xml" => quote!(Xml),
+                        _ => {
+                            return Err(syn::Error::new(
+                                value.span(),
+                                "expected one of default, hex, pid, json or xml",
+                            ))
+                        }
+                    };
+                } else {
+                    return Err(meta.error("unsupported etw field attribute"));
+                }
+                Ok(())
+            })?;
+        }
+
+        if !skip {
+            adds.push(quote! {
+                ::log_etw::typed_events::EventField::add_field(
+                    &self.#ident,
+                    #field_name,
+                    ::log_etw::typed_events::FieldFormat::#format,
+                    writer,
+                );
+            });
+        }
+    }
+
+    expand_impl(&input, provider, name, level, keyword, opcode, adds)
+}
+
+fn expand_impl(
+    input: &DeriveInput,
+    provider: LitStr,
+    name: LitStr,
+    level: proc_macro2::TokenStream,
+    keyword: proc_macro2::TokenStream,
+    opcode: proc_macro2::TokenStream,
+    adds: Vec<proc_macro2::TokenStream>,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::log_etw::typed_events::LogEtwEvent for #ident #ty_generics #where_clause {
+            const PROVIDER: &'static str = #provider;
+            const NAME: &'static str = #name;
+            const LEVEL: ::log_etw::__private::log::Level = ::log_etw::__private::log::Level::#level;
+            const KEYWORD: u64 = #keyword;
+            const OPCODE: u8 = #opcode;
+
+            fn provider_slot(
+            ) -> ::core::option::Option<&'static ::log_etw::typed_events::ProviderSlot> {
+                static SLOT: ::log_etw::typed_events::ProviderSlot =
+                    ::log_etw::typed_events::ProviderSlot::new();
+                ::core::option::Option::Some(&SLOT)
+            }
+
+            #[allow(unused_variables)]
+            fn add_fields(&self, writer: &mut ::log_etw::typed_events::EventWriter) {
+                #(#adds)*
+            }
+        }
+    })
+}
+
+/// Rewrite the `name = value` fields of a `log_etw::etw_event!` call into string fields,
+/// then call the `write_event!` macro of the platform's crate with the result.
+///
+/// `__static_event!($crate, path::to::write_event, (provider, "Name", options...), fields...)`
+#[doc(hidden)]
+#[proc_macro]
+pub fn __static_event(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as StaticEvent);
+    let StaticEvent {
+        krate,
+        write_event,
+        prefix,
+        fields,
+    } = input;
+
+    let fields = fields.into_iter().map(|field| match field {
+        StaticField::Display(name, value) => {
+            let name = LitStr::new(&name.to_string(), name.span());
+            quote! {
+                str8(#name, &#krate::static_events::DisplayField::new(&(#value)))
+            }
+        }
+        StaticField::Typed(tokens) => tokens,
+    });
+
+    quote!(#write_event!(#prefix #(, #fields)*)).into()
+}
+
+struct StaticEvent {
+    krate: proc_macro2::TokenTree,
+    write_event: syn::Path,
+    prefix: proc_macro2::TokenStream,
+    fields: Vec<StaticField>,
+}
+
+enum StaticField {
+    /// `name = value`, written as a string.
+    Display(syn::Ident, syn::Expr),
+    /// A field or option in the syntax of `write_event!`, such as `u32("Name", &value)`.
+    Typed(proc_macro2::TokenStream),
+}
+
+impl syn::parse::Parse for StaticEvent {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let krate = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let write_event = input.parse()?;
+        input.parse::<syn::Token![,]>()?;
+        let prefix;
+        syn::parenthesized!(prefix in input);
+        let prefix = prefix.parse()?;
+
+        let mut fields = Vec::new();
+        while !input.is_empty() {
+            input.parse::<syn::Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            if input.peek(syn::Ident)
+                && input.peek2(syn::Token![=])
+                && !input.peek2(syn::Token![==])
+            {
+                let name = input.parse()?;
+                input.parse::<syn::Token![=]>()?;
+                fields.push(StaticField::Display(name, input.parse()?));
+            } else {
+                // Typed fields and options are a name and a group, with no commas outside the group
+                let mut tokens = proc_macro2::TokenStream::new();
+                while !input.is_empty() && !input.peek(syn::Token![,]) {
+                    tokens.extend(std::iter::once(input.parse::<proc_macro2::TokenTree>()?));
+                }
+                fields.push(StaticField::Typed(tokens));
+            }
+        }
+
+        Ok(StaticEvent {
+            krate,
+            write_event,
+            prefix,
+            fields,
+        })
+    }
+}
@@ -0,0 +1,362 @@
+//! Writing records on a background thread, for callers that cannot wait for them to be encoded.
+
+use crate::logger::{LoggerCore, OverflowPolicy, QueueStats, RecordThread};
+use crossbeam_queue::ArrayQueue;
+use std::borrow::Cow;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, SystemTime};
+
+// How long waiting threads sleep before checking the queue again, in case they missed a wakeup
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A record captured by the logging thread, with everything needed to write it later.
+pub(crate) struct QueuedRecord {
+    level: log::Level,
+    target: &'static str,
+    message: Cow<'static, str>,
+    module_path: Option<Cow<'static, str>>,
+    file: Option<Cow<'static, str>>,
+    line: Option<u32>,
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    key_values: Vec<(String, owned_kv::OwnedValue)>,
+    timestamp: SystemTime,
+    thread: RecordThread,
+}
+
+fn to_owned_static(
+    borrowed: Option<&str>,
+    fixed: Option<&'static str>,
+) -> Option<Cow<'static, str>> {
+    fixed
+        .map(Cow::Borrowed)
+        .or_else(|| borrowed.map(|value| Cow::Owned(value.to_owned())))
+}
+
+impl QueuedRecord {
+    pub(crate) fn capture(
+        record: &log::Record,
+        timestamp: SystemTime,
+        thread: RecordThread,
+    ) -> Self {
+        QueuedRecord {
+            level: record.level(),
+            // The target is usually the module path. Otherwise it names a provider, which is
+            // never freed either, so interning it keeps the callsite cache keyed on one address.
+            target: match record.module_path_static() {
+                Some(module_path) if module_path == record.target() => module_path,
+                _ => crate::event::intern(record.target()),
+            },
+            message: match record.args().as_str() {
+                Some(message) => Cow::Borrowed(message),
+                None => Cow::Owned(record.args().to_string()),
+            },
+            module_path: to_owned_static(record.module_path(), record.module_path_static()),
+            file: to_owned_static(record.file(), record.file_static()),
+            line: record.line(),
+            #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+            key_values: owned_kv::capture(record.key_values()),
+            timestamp,
+            thread,
+        }
+    }
+
+    fn write(self, core: &LoggerCore) {
+        let config = core.exporter_config.load();
+        let QueuedRecord {
+            level,
+            target,
+            message,
+            module_path,
+            file,
+            line,
+            #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+            key_values,
+            timestamp,
+            thread,
+        } = self;
+
+        thread.scope(|| {
+            let mut builder = log::Record::builder();
+            builder
+                .level(level)
+                .target(target)
+                .module_path(module_path.as_deref())
+                .file(file.as_deref())
+                .line(line);
+            #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+            builder.key_values(&key_values);
+
+            core.write(
+                &builder.args(format_args!("{}", message)).build(),
+                timestamp,
+                &config,
+            );
+        });
+    }
+}
+
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+mod owned_kv {
+    use crate::event::{meta, owned_meta};
+    use log::kv::{Error, Key, Source, ToValue, Value, Visitor};
+
+    /// A key-value that outlives its record. Values that are not primitives or `_meta`
+    /// overrides are kept as their `Display` string.
+    pub(crate) enum OwnedValue {
+        Meta(owned_meta),
+        Str(String),
+        U64(u64),
+        I64(i64),
+        F64(f64),
+        Bool(bool),
+        Char(char),
+    }
+
+    impl ToValue for OwnedValue {
+        fn to_value(&self) -> Value<'_> {
+            match self {
+                OwnedValue::Meta(meta) => meta.to_value(),
+                OwnedValue::Str(value) => Value::from(value.as_str()),
+                OwnedValue::U64(value) => Value::from(*value),
+                OwnedValue::I64(value) => Value::from(*value),
+                OwnedValue::F64(value) => Value::from(*value),
+                OwnedValue::Bool(value) => Value::from(*value),
+                OwnedValue::Char(value) => Value::from(*value),
+            }
+        }
+    }
+
+    fn owned_meta(value: &Value) -> Option<owned_meta> {
+        if let Some(meta) = value.downcast_ref::<meta>() {
+            Some(owned_meta {
+                provider: meta.provider.into(),
+                event_name: meta.event_name.into(),
+                keyword: meta.keyword,
+                opcode: meta.opcode,
+                level: meta.level,
+                id: meta.id,
+                version: meta.version,
+                tag: meta.tag,
+            })
+        } else {
+            value.downcast_ref::<owned_meta>().map(|meta| owned_meta {
+                provider: meta.provider.clone(),
+                event_name: meta.event_name.clone(),
+                keyword: meta.keyword,
+                opcode: meta.opcode,
+                level: meta.level,
+                id: meta.id,
+                version: meta.version,
+                tag: meta.tag,
+            })
+        }
+    }
+
+    pub(crate) fn capture(source: &dyn Source) -> Vec<(String, OwnedValue)> {
+        struct Capture(Vec<(String, OwnedValue)>);
+
+        impl<'kvs> Visitor<'kvs> for Capture {
+            fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+                let value = if let Some(meta) = owned_meta(&value) {
+                    OwnedValue::Meta(meta)
+                } else if let Some(value) = value.to_bool() {
+                    OwnedValue::Bool(value)
+                } else if let Some(value) = value.to_u64() {
+                    OwnedValue::U64(value)
+                } else if let Some(value) = value.to_i64() {
+                    OwnedValue::I64(value)
+                } else if let Some(value) = value.to_f64() {
+                    OwnedValue::F64(value)
+                } else if let Some(value) = value.to_char() {
+                    OwnedValue::Char(value)
+                } else {
+                    OwnedValue::Str(value.to_string())
+                };
+                self.0.push((key.as_str().to_owned(), value));
+                Ok(())
+            }
+        }
+
+        let mut capture = Capture(Vec::new());
+        let _ = source.visit(&mut capture);
+        capture.0
+    }
+}
+
+struct Shared {
+    queue: ArrayQueue<QueuedRecord>,
+    overflow: OverflowPolicy,
+    stopping: AtomicBool,
+    queued: AtomicU64,
+    written: AtomicU64,
+    dropped: AtomicU64,
+    blocked: AtomicU64,
+    panicked: AtomicU64,
+    // Records that are no longer queued, whether written, dropped to make room or panicked.
+    // `drain` waits for this to catch up with `queued`.
+    done: AtomicU64,
+    // Notified by the background thread whenever it empties the queue
+    progress: Mutex<()>,
+    progressed: Condvar,
+}
+
+impl Shared {
+    fn wait_for_progress(&self) {
+        let guard = self.progress.lock().unwrap();
+        let _ = self.progressed.wait_timeout(guard, POLL_INTERVAL);
+    }
+}
+
+/// A bounded queue of records, and the thread that writes them.
+pub(crate) struct BackgroundWriter {
+    shared: Arc<Shared>,
+    worker: Thread,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl BackgroundWriter {
+    pub(crate) fn spawn(core: Arc<LoggerCore>, capacity: usize, overflow: OverflowPolicy) -> Self {
+        let shared = Arc::new(Shared {
+            queue: ArrayQueue::new(capacity.max(1)),
+            overflow,
+            stopping: AtomicBool::new(false),
+            queued: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            panicked: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            progress: Mutex::new(()),
+            progressed: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("log-etw background writer".to_owned())
+                .spawn(move || loop {
+                    while let Some(record) = shared.queue.pop() {
+                        // A record that panics is dropped, rather than stopping the thread
+                        // and leaving every later record in the queue
+                        let counter =
+                            match panic::catch_unwind(AssertUnwindSafe(|| record.write(&core))) {
+                                Ok(()) => &shared.written,
+                                Err(_) => &shared.panicked,
+                            };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        shared.done.fetch_add(1, Ordering::Release);
+                    }
+
+                    drop(shared.progress.lock().unwrap());
+                    shared.progressed.notify_all();
+
+                    if shared.stopping.load(Ordering::Acquire) && shared.queue.is_empty() {
+                        break;
+                    }
+                    thread::park_timeout(Duration::from_millis(100));
+                })
+                .expect("failed to spawn the background writer thread")
+        };
+
+        BackgroundWriter {
+            shared,
+            worker: thread.thread().clone(),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    fn on_worker(&self) -> bool {
+        thread::current().id() == self.worker.id()
+    }
+
+    /// Whether the background thread is still writing records. It is not once it has been
+    /// stopped, or if it panicked outside of writing a record.
+    fn worker_running(&self) -> bool {
+        self.thread
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(false, |thread| !thread.is_finished())
+    }
+
+    /// Queue a record, applying the overflow policy if the queue is full.
+    pub(crate) fn push(&self, record: QueuedRecord) {
+        let shared = &*self.shared;
+        let mut record = record;
+        loop {
+            record = match shared.queue.push(record) {
+                Ok(()) => break,
+                Err(record) => record,
+            };
+
+            match shared.overflow {
+                OverflowPolicy::DropOldest => {
+                    if shared.queue.force_push(record).is_some() {
+                        shared.dropped.fetch_add(1, Ordering::Relaxed);
+                        shared.done.fetch_add(1, Ordering::Release);
+                    }
+                    break;
+                }
+                // The background thread cannot wait for itself, and nothing makes room
+                // in the queue once it has exited
+                OverflowPolicy::Block if !self.on_worker() && self.worker_running() => {
+                    shared.blocked.fetch_add(1, Ordering::Relaxed);
+                    self.worker.unpark();
+                    shared.wait_for_progress();
+                }
+                _ => {
+                    shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+        }
+
+        shared.queued.fetch_add(1, Ordering::Release);
+        self.worker.unpark();
+    }
+
+    /// Wait until every record queued so far has been written or dropped.
+    pub(crate) fn drain(&self) {
+        if self.on_worker() {
+            return;
+        }
+
+        let shared = &*self.shared;
+        let queued = shared.queued.load(Ordering::Acquire);
+        while shared.done.load(Ordering::Acquire) < queued {
+            // The queue can never drain once the background thread has exited
+            if !self.worker_running() {
+                return;
+            }
+            self.worker.unpark();
+            shared.wait_for_progress();
+        }
+    }
+
+    /// Write the queued records and stop the background thread.
+    /// Records queued afterwards are never written.
+    pub(crate) fn stop(&self) {
+        self.shared.stopping.store(true, Ordering::Release);
+        self.worker.unpark();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            if !self.on_worker() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        let shared = &*self.shared;
+        QueueStats {
+            queued: shared.queued.load(Ordering::Relaxed),
+            written: shared.written.load(Ordering::Relaxed),
+            dropped: shared.dropped.load(Ordering::Relaxed),
+            blocked: shared.blocked.load(Ordering::Relaxed),
+            panicked: shared.panicked.load(Ordering::Relaxed),
+            running: self.worker_running(),
+        }
+    }
+}
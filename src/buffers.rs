@@ -0,0 +1,115 @@
+use crate::error::Error;
+#[cfg(target_os = "windows")]
+use crate::etw::build_record;
+use crate::logger::{EventOptions, ExporterBuilder, ExporterConfig};
+#[cfg(target_os = "linux")]
+use crate::user_events::build_record;
+#[cfg(target_os = "linux")]
+use eventheader_dynamic::EventBuilder;
+use std::cell::RefCell;
+use std::time::SystemTime;
+#[cfg(target_os = "windows")]
+use tracelogging_dynamic::EventBuilder;
+
+// Enough for a few levels of nested logging. Deeper nesting still works, but the extra
+// buffers are freed afterwards instead of being kept for reuse.
+const MAX_POOLED_BUFFERS: usize = 4;
+
+/// An event builder and a scratch buffer for formatting field values.
+pub(crate) struct EventBuffers {
+    pub(crate) eb: EventBuilder,
+    pub(crate) buffer: Vec<u8>,
+}
+
+impl EventBuffers {
+    fn new() -> Self {
+        EventBuffers {
+            eb: EventBuilder::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Forget the last event, keeping the memory for the next one.
+    fn clear(&mut self) {
+        self.buffer.clear();
+        #[cfg(target_os = "linux")]
+        self.eb.reset("", 0);
+        #[cfg(target_os = "windows")]
+        self.eb.reset("", tracelogging::Level::LogAlways, 0, 0);
+    }
+}
+
+// Buffers are taken out of the pool while an event is being built, rather than borrowed,
+// so a Display or Value implementation that logs gets its own buffers instead of panicking.
+thread_local! {
+    static EVENT_BUFFER_POOL: RefCell<Vec<EventBuffers>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f` with buffers from the current thread's pool. The buffers are empty, whatever
+/// the last caller left in them. Reentrant calls get separate buffers.
+pub(crate) fn with_event_buffers<R>(f: impl FnOnce(&mut EventBuffers) -> R) -> R {
+    // The pool is unavailable while the thread is being torn down
+    let mut buffers = EVENT_BUFFER_POOL
+        .try_with(|pool| pool.borrow_mut().pop())
+        .ok()
+        .flatten()
+        .unwrap_or_else(EventBuffers::new);
+
+    let result = f(&mut buffers);
+    buffers.clear();
+
+    let _ = EVENT_BUFFER_POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(buffers);
+        }
+    });
+
+    result
+}
+
+/// Builds records in the current thread's event buffers without writing them,
+/// so that the allocation tests can measure encoding on its own.
+#[doc(hidden)]
+pub struct RecordEncoder(ExporterConfig);
+
+impl RecordEncoder {
+    pub fn new(builder: ExporterBuilder) -> Result<Self, Error> {
+        builder.into_config().map(RecordEncoder)
+    }
+
+    pub fn encode(&self, record: &log::Record) {
+        let options = EventOptions::new(record.level());
+        with_event_buffers(|buffers| {
+            build_record(buffers, SystemTime::now(), &options, record, &self.0)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_buffers() {
+        let outer = with_event_buffers(|outer| {
+            outer.buffer.extend_from_slice(b"outer");
+
+            let inner = with_event_buffers(|inner| {
+                assert!(inner.buffer.is_empty());
+                inner.buffer.extend_from_slice(b"inner");
+                inner.buffer.as_ptr() as usize
+            });
+
+            assert_eq!(outer.buffer, b"outer");
+            assert_ne!(outer.buffer.as_ptr() as usize, inner);
+            outer.buffer.as_ptr() as usize
+        });
+
+        // Both buffers went back to the pool empty, and the most recently returned one is reused
+        with_event_buffers(|buffers| {
+            assert!(buffers.buffer.is_empty());
+            assert_eq!(buffers.buffer.as_ptr() as usize, outer);
+        });
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of timestamps for log events.
+/// Set with [`ExporterBuilder::with_clock`](crate::logger::ExporterBuilder::with_clock).
+pub trait Clock: Send + Sync {
+    /// The timestamp to record for an event that is being logged right now.
+    fn now(&self) -> SystemTime;
+}
+
+/// The default clock. Returns the current wall-clock time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that never goes backwards.
+/// Timestamps are a fixed wall-clock base time plus the monotonic time elapsed
+/// since the clock was created, so they are unaffected by later adjustments
+/// to the system clock.
+#[derive(Clone, Copy, Debug)]
+pub struct MonotonicClock {
+    base_time: SystemTime,
+    base_instant: Instant,
+}
+
+impl MonotonicClock {
+    /// Create a clock that starts at the current wall-clock time.
+    pub fn new() -> Self {
+        Self::with_base_time(SystemTime::now())
+    }
+
+    /// Create a clock that starts at `base_time`.
+    pub fn with_base_time(base_time: SystemTime) -> Self {
+        MonotonicClock {
+            base_time,
+            base_instant: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> SystemTime {
+        self.base_time + self.base_instant.elapsed()
+    }
+}
+
+/// A clock that only changes when told to.
+/// Useful for tests that need byte-stable events.
+#[derive(Debug)]
+pub struct FixedClock {
+    nanos_since_epoch: AtomicU64,
+}
+
+impl FixedClock {
+    /// Create a clock that always returns `time`.
+    /// Times before the Unix epoch are clamped to the epoch.
+    pub fn new(time: SystemTime) -> Self {
+        FixedClock {
+            nanos_since_epoch: AtomicU64::new(crate::logger::unix_nanos(time)),
+        }
+    }
+
+    /// Change the time returned by the clock.
+    pub fn set(&self, time: SystemTime) {
+        self.nanos_since_epoch
+            .store(crate::logger::unix_nanos(time), Ordering::Relaxed);
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.nanos_since_epoch
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH
+            + Duration::from_nanos(self.nanos_since_epoch.load(Ordering::Relaxed))
+    }
+}
+
+impl<T: Clock + ?Sized> Clock for std::sync::Arc<T> {
+    fn now(&self) -> SystemTime {
+        (**self).now()
+    }
+}
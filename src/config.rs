@@ -0,0 +1,522 @@
+//! Logger configuration files.
+//!
+//! [`ExporterBuilder::from_config_file`] reads the same options as the builder methods
+//! from a TOML file, or a JSON file if the path ends in `.json`. Every setting is optional
+//! except `provider`:
+//!
+//! ```toml
+//! provider = "MyCompany_MyService"
+//! # provider_id = "{b3864c38-4273-58c5-545b-8b3608343471}"
+//! # group = "mygroup"                  # A group name on Linux, a GUID on Windows
+//! providers = ["MyCompany_MyComponent"]
+//! max_level = "info"
+//! json = false
+//! common_schema = false
+//! timestamp_format = "rfc3339"         # "system_time", "file_time", "unix_nanos", "rfc3339" or "none"
+//! keywords = [1, 0x10]
+//! event_sets = [{ level = 1, keyword = 0x20 }]
+//! registration_policy = "lazy"         # "lazy" or "declared_only"
+//! fallback = "stderr"                  # "silent", "stderr" or { file = "/var/log/my.log" }
+//! console = "compact"                  # "compact" or "json"
+//! console_colors = true
+//! native_events = true
+//!
+//! [json_file]                          # Only `path` is required
+//! path = "/var/log/my-service.jsonl"
+//! max_bytes = 10485760
+//! max_age_secs = 86400
+//! keep = 5
+//!
+//! [background]                         # Only `capacity` is required
+//! capacity = 4096
+//! overflow = "drop_newest"             # "drop_newest", "drop_oldest" or "block"
+//!
+//! [rate_limit]                         # Fields that are left out keep their default
+//! scope = "event_name"                 # "provider", "event_name" or "callsite"
+//! burst = 1000
+//! per_second = 100
+//! summary_interval_secs = 60
+//!
+//! [field_set]                          # Fields that are left out keep their default
+//! thread_id = true
+//! file = false
+//!
+//! [[routes]]                           # The first route whose target prefix matches is used
+//! target = "hyper::"
+//! provider = "MyCompany_MyService_Http"
+//!
+//! [[keyword_map]]                      # The first entry whose target prefix matches is used
+//! target = "my_service::db"
+//! keyword = 0x4
+//! ```
+//!
+//! [`LoggerHandle::watch_config_file`] re-applies the file to the installed logger
+//! whenever it changes, so logging can be tuned without restarting the process.
+
+use crate::error::Error;
+use crate::logger::{new_logger, ExporterBuilder, FieldSet, LoggerHandle, ProviderGroup};
+use crate::logger::{ConsoleFormat, Fallback, FileRotation, OverflowPolicy};
+use crate::logger::{RateLimit, RateLimitScope, RegistrationPolicy, TimestampFormat};
+use serde_derive::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracelogging::Guid;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    provider: String,
+    provider_id: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    providers: Vec<String>,
+    max_level: Option<String>,
+    json: Option<bool>,
+    common_schema: Option<bool>,
+    timestamp_format: Option<TimestampFormat>,
+    field_set: Option<FieldSet>,
+    keywords: Option<Vec<u64>>,
+    #[serde(default)]
+    event_sets: Vec<EventSet>,
+    registration_policy: Option<RegistrationPolicy>,
+    fallback: Option<Fallback>,
+    console: Option<ConsoleFormat>,
+    console_colors: Option<bool>,
+    native_events: Option<bool>,
+    json_file: Option<JsonFile>,
+    background: Option<Background>,
+    rate_limit: Option<RateLimitSection>,
+    #[serde(default)]
+    routes: Vec<Route>,
+    #[serde(default)]
+    keyword_map: Vec<TargetKeyword>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonFile {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_age_secs: Option<u64>,
+    keep: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Background {
+    capacity: usize,
+    #[serde(default)]
+    overflow: OverflowPolicy,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitSection {
+    scope: Option<RateLimitScope>,
+    burst: Option<u32>,
+    per_second: Option<u32>,
+    summary_interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Route {
+    target: String,
+    provider: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetKeyword {
+    target: String,
+    keyword: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventSet {
+    level: u8,
+    keyword: u64,
+}
+
+impl ConfigFile {
+    fn parse(path: &Path, contents: &str) -> Result<Self, String> {
+        if path.extension().map_or(false, |ext| ext == "json") {
+            serde_json::from_str(contents).map_err(|err| err.to_string())
+        } else {
+            toml::from_str(contents).map_err(|err| err.to_string())
+        }
+    }
+
+    fn into_builder(self) -> Result<ExporterBuilder, String> {
+        let mut builder = new_logger(&self.provider);
+
+        if let Some(id) = self.provider_id {
+            let guid = Guid::try_parse(&id)
+                .ok_or_else(|| format!("provider_id {:?} is not a GUID", id))?;
+            builder = builder.with_provider_id(guid);
+        }
+
+        if let Some(group) = self.group {
+            builder.provider_group =
+                ProviderGroup::parse(&group).map_err(|reason| format!("group {}", reason))?;
+        }
+
+        builder = builder.with_providers(self.providers);
+        for route in self.routes {
+            builder = builder.with_route(route.target, route.provider);
+        }
+        for entry in self.keyword_map {
+            builder = builder.with_target_keyword(entry.target, entry.keyword);
+        }
+
+        if let Some(level) = self.max_level {
+            builder = builder.with_max_level(
+                level
+                    .parse()
+                    .map_err(|_| format!("max_level {:?} is not a log level", level))?,
+            );
+        }
+
+        if let Some(json) = self.json {
+            builder.json = json;
+        }
+        if let Some(common_schema) = self.common_schema {
+            builder.emit_common_schema_events = common_schema;
+        }
+        if let Some(format) = self.timestamp_format {
+            builder = builder.with_timestamp_format(format);
+        }
+        if let Some(field_set) = self.field_set {
+            builder = builder.with_field_set(field_set);
+        }
+        if let Some(keywords) = self.keywords {
+            builder = builder.with_keywords(keywords);
+        }
+        for set in self.event_sets {
+            builder = builder.with_event_set(set.level, set.keyword);
+        }
+        if let Some(policy) = self.registration_policy {
+            builder = builder.with_registration_policy(policy);
+        }
+        if let Some(fallback) = self.fallback {
+            builder = builder.with_fallback(fallback);
+        }
+        builder.console = self.console;
+        if let Some(colors) = self.console_colors {
+            builder.console_colors = colors;
+        }
+        if let Some(native_events) = self.native_events {
+            builder.native_events = native_events;
+        }
+        if let Some(file) = self.json_file {
+            let defaults = FileRotation::default();
+            let rotation = FileRotation {
+                max_bytes: file.max_bytes.or(defaults.max_bytes),
+                max_age: file.max_age_secs.map(Duration::from_secs),
+                keep: file.keep.unwrap_or(defaults.keep),
+            };
+            builder = builder.with_json_file(file.path, rotation);
+        }
+        if let Some(background) = self.background {
+            builder = builder.with_background_writer(background.capacity, background.overflow);
+        }
+        if let Some(section) = self.rate_limit {
+            let defaults = RateLimit::default();
+            let mut limit = match section.per_second {
+                Some(per_second) => {
+                    RateLimit::per_second(per_second, section.burst.unwrap_or(defaults.burst))
+                }
+                None => RateLimit {
+                    burst: section.burst.unwrap_or(defaults.burst),
+                    ..defaults
+                },
+            };
+            if let Some(scope) = section.scope {
+                limit.scope = scope;
+            }
+            if let Some(secs) = section.summary_interval_secs {
+                limit.summary_interval = Duration::from_secs(secs);
+            }
+            builder = builder.with_rate_limit(limit);
+        }
+
+        Ok(builder)
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<ExporterBuilder, Error> {
+    let invalid = |reason: String| Error::InvalidConfig {
+        origin: path.display().to_string(),
+        reason,
+    };
+
+    let contents = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let builder = ConfigFile::parse(path, &contents)
+        .and_then(ConfigFile::into_builder)
+        .map_err(invalid)?;
+
+    // Report bad names now, rather than when the logger is installed
+    builder.validate_config()?;
+    Ok(builder)
+}
+
+impl ExporterBuilder {
+    /// Create a builder from a TOML or JSON configuration file.
+    /// See the [`config`](crate::config) module for the format.
+    ///
+    /// The builder can be configured further before it is installed.
+    /// Requires the `config` feature.
+    pub fn from_config_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        read_config_file(path.as_ref())
+    }
+}
+
+impl LoggerHandle {
+    /// Check the configuration file at `path` every `interval`, and reconfigure the logger
+    /// with it whenever it changes. See [`ConfigWatcher::spawn`].
+    /// Requires the `config` feature.
+    pub fn watch_config_file(&self, path: impl Into<PathBuf>, interval: Duration) -> ConfigWatcher {
+        let handle = self.clone();
+        ConfigWatcher::spawn(path, interval, move |builder| handle.reconfigure(builder))
+    }
+}
+
+/// Watches a configuration file. Dropping the watcher stops it.
+pub struct ConfigWatcher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+    last_error: Arc<Mutex<Option<Error>>>,
+}
+
+impl ConfigWatcher {
+    /// Check the configuration file at `path` every `interval`, and call `reconfigure`
+    /// with a builder created from it whenever it changes, such as with
+    /// [`EtwEventHeaderLogger::reconfigure`](crate::logger::EtwEventHeaderLogger::reconfigure).
+    /// Options that are not in the file return to their defaults, including the clock.
+    ///
+    /// If the file cannot be read or is not valid, `reconfigure` is not called and
+    /// the error is available from [`last_error`](Self::last_error).
+    pub fn spawn(
+        path: impl Into<PathBuf>,
+        interval: Duration,
+        mut reconfigure: impl FnMut(ExporterBuilder) -> Result<(), Error> + Send + 'static,
+    ) -> ConfigWatcher {
+        let path = path.into();
+        let last_error = Arc::new(Mutex::new(None));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        // Configuration files are small, so compare their contents rather than
+        // relying on modification times, which can be coarse.
+        let mut last_contents = std::fs::read(&path).ok();
+
+        let thread = {
+            let last_error = last_error.clone();
+            thread::Builder::new()
+                .name("log-etw config watcher".to_owned())
+                .spawn(move || {
+                    // Wakes up early, with Disconnected, when the watcher is stopped
+                    while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval)
+                    {
+                        let contents = std::fs::read(&path).ok();
+                        if contents == last_contents {
+                            continue;
+                        }
+                        last_contents = contents;
+
+                        let result = read_config_file(&path).and_then(&mut reconfigure);
+                        *last_error.lock().unwrap() = result.err();
+                    }
+                })
+                .expect("failed to spawn the config watcher thread")
+        };
+
+        ConfigWatcher {
+            stop: Some(stop),
+            thread: Some(thread),
+            last_error,
+        }
+    }
+
+    /// Why the most recent change to the file was not applied, if it was not.
+    pub fn last_error(&self) -> Option<Error> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    /// Stop watching the file, and wait for the watcher thread to exit.
+    pub fn stop(self) {}
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_files() {
+        let path = Path::new("config.toml");
+        let builder = ConfigFile::parse(
+            path,
+            r#"
+                provider = "LogEtwConfigFile"
+                providers = ["LogEtwConfigOther"]
+                max_level = "warn"
+                common_schema = true
+                timestamp_format = "unix_nanos"
+                keywords = [1, 0x10]
+                event_sets = [{ level = 1, keyword = 0x20 }]
+                registration_policy = "declared_only"
+                fallback = { file = "fallback.log" }
+
+                [json_file]
+                path = "events.jsonl"
+                max_age_secs = 60
+
+                [background]
+                capacity = 256
+                overflow = "block"
+
+                [rate_limit]
+                scope = "callsite"
+                per_second = 10
+
+                [field_set]
+                thread_id = true
+                file = false
+
+                [[routes]]
+                target = "hyper::"
+                provider = "LogEtwConfigHttp"
+
+                [[keyword_map]]
+                target = "my_service::db"
+                keyword = 0x4
+            "#,
+        )
+        .and_then(ConfigFile::into_builder)
+        .unwrap();
+
+        assert_eq!(builder.provider_name, "LogEtwConfigFile");
+        assert_eq!(builder.providers, ["LogEtwConfigOther", "LogEtwConfigHttp"]);
+        assert_eq!(
+            builder.routes,
+            [("hyper::".to_owned(), "LogEtwConfigHttp".to_owned())]
+        );
+        assert_eq!(builder.keyword_map, [("my_service::db".to_owned(), 4)]);
+        assert_eq!(builder.max_level, log::LevelFilter::Warn);
+        assert!(builder.emit_common_schema_events);
+        assert_eq!(builder.timestamp_format, TimestampFormat::UnixNanos);
+        assert_eq!(builder.event_sets.len(), 11);
+        assert_eq!(
+            builder.registration_policy,
+            RegistrationPolicy::DeclaredOnly
+        );
+        assert!(builder.field_set.thread_id);
+        assert!(!builder.field_set.file);
+        assert!(builder.field_set.line);
+        assert_eq!(builder.fallback, Fallback::File("fallback.log".into()));
+        assert_eq!(
+            builder.json_file,
+            Some((
+                PathBuf::from("events.jsonl"),
+                FileRotation {
+                    max_age: Some(Duration::from_secs(60)),
+                    ..FileRotation::default()
+                }
+            ))
+        );
+        assert_eq!(builder.background, Some((256, OverflowPolicy::Block)));
+        assert_eq!(
+            builder.rate_limit,
+            Some(RateLimit {
+                scope: RateLimitScope::Callsite,
+                refill: Duration::from_millis(100),
+                ..RateLimit::default()
+            })
+        );
+
+        let builder = ConfigFile::parse(
+            Path::new("config.json"),
+            r#"{ "provider": "LogEtwConfigJson", "json": true, "console": "json" }"#,
+        )
+        .and_then(ConfigFile::into_builder)
+        .unwrap();
+        assert_eq!(builder.provider_name, "LogEtwConfigJson");
+        assert!(builder.json);
+        assert_eq!(builder.console, Some(ConsoleFormat::Json));
+
+        assert!(ConfigFile::parse(path, "max_level = \"warn\"").is_err());
+        assert!(ConfigFile::parse(path, "provider = \"A\"\nunknown = 1").is_err());
+        assert!(
+            ConfigFile::parse(path, "provider = \"A\"\nmax_level = \"loud\"")
+                .and_then(ConfigFile::into_builder)
+                .is_err()
+        );
+
+        assert!(matches!(
+            ExporterBuilder::from_config_file("does-not-exist.toml"),
+            Err(Error::InvalidConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn config_watcher() {
+        let path = std::env::temp_dir().join(format!("log-etw-{}.toml", std::process::id()));
+        std::fs::write(&path, "provider = \"LogEtwWatched\"\n").unwrap();
+
+        let logger = Arc::new(
+            ExporterBuilder::from_config_file(&path)
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+        let max_level = || logger.core.exporter_config.load().max_level;
+        let wait_for = |done: &dyn Fn() -> bool| {
+            for _ in 0..500 {
+                if done() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("the watcher did not apply the change");
+        };
+
+        let watcher = {
+            let logger = logger.clone();
+            ConfigWatcher::spawn(&path, Duration::from_millis(10), move |builder| {
+                logger.reconfigure(builder)
+            })
+        };
+
+        // Invalid changes are reported and leave the configuration alone
+        std::fs::write(
+            &path,
+            "provider = \"LogEtwWatched\"\nmax_level = \"loud\"\n",
+        )
+        .unwrap();
+        wait_for(&|| watcher.last_error().is_some());
+        assert_eq!(max_level(), log::LevelFilter::Trace);
+
+        std::fs::write(
+            &path,
+            "provider = \"LogEtwWatched\"\nmax_level = \"error\"\n",
+        )
+        .unwrap();
+        // The error is cleared just after the change is applied
+        wait_for(&|| max_level() == log::LevelFilter::Error && watcher.last_error().is_none());
+
+        watcher.stop();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,54 @@
+//! Errors returned when configuring or installing a logger.
+
+use std::fmt;
+
+/// An error from [`ExporterBuilder::try_install`](crate::logger::ExporterBuilder::try_install).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// Another logger, possibly from a different crate, is already the global logger.
+    AlreadyInstalled,
+    /// A provider name cannot be registered with the platform's tracing API.
+    InvalidProviderName {
+        /// The rejected provider name.
+        name: String,
+        /// Why the name was rejected.
+        reason: &'static str,
+    },
+    /// The provider group is not valid for the platform.
+    InvalidProviderGroup {
+        /// Why the group was rejected.
+        reason: &'static str,
+    },
+    /// The target has neither ETW nor user_events.
+    UnsupportedPlatform,
+    /// A configuration setting could not be read or is not valid.
+    InvalidConfig {
+        /// Where the setting came from, such as a file path.
+        origin: String,
+        /// What is wrong with it.
+        reason: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AlreadyInstalled => f.write_str("a global logger is already installed"),
+            Error::InvalidProviderName { name, reason } => {
+                write!(f, "invalid provider name {:?}: {}", name, reason)
+            }
+            Error::InvalidProviderGroup { reason } => {
+                write!(f, "invalid provider group: {}", reason)
+            }
+            Error::UnsupportedPlatform => {
+                f.write_str("this platform supports neither ETW nor user_events")
+            }
+            Error::InvalidConfig { origin, reason } => {
+                write!(f, "invalid configuration in {}: {}", origin, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,367 @@
+//! Per-record overrides for how events are written.
+//!
+//! By default a record is written to the provider named by its target, as an event called
+//! "Event" with keyword 1 and a level mapped from the [`log::Level`]. With key-values
+//! enabled (`kv_unstable` or `kv_unstable_json`), a record can override these with
+//! reserved keys:
+//!
+//! ```ignore
+//! info!(_provider = "MyProvider", _event = "RequestStarted", _keyword = 0x2, _opcode = 1; "Started");
+//!
+//! // Or all at once:
+//! info!(_meta = evt_meta!("MyProvider", "RequestStarted", 0x2, opcode: opcode::START); "Started");
+//! ```
+//!
+//! | Key          | Value                                                         |
+//! |--------------|---------------------------------------------------------------|
+//! | `_meta`      | An [`event::meta`](meta), usually created with [`evt_meta!`](crate::evt_meta) |
+//! | `_provider`  | The provider name                                             |
+//! | `_event`     | The event name                                                |
+//! | `_keyword`   | The event keyword                                             |
+//! | `_level`     | A raw event level from 1 to 255                               |
+//! | `_opcode`    | The event opcode. See [`opcode`]                              |
+//! | `_id`        | A stable event ID                                             |
+//! | `_version`   | The version of the event schema identified by `_id`           |
+//! | `_tag`       | The event tag, up to `0xFFFF` on both platforms. See [`meta::tag`] |
+//! | `_timestamp` | Nanoseconds since the Unix epoch, or an RFC 3339 string       |
+//!
+//! Individual keys take precedence over `_meta`. Reserved keys are not written as event fields.
+//! Without key-values, only the provider can be chosen, through the record's target.
+//!
+//! Provider and event names that are only known at runtime can be passed with `_provider`
+//! and `_event`, or with an [`owned_meta`] as `_meta`. [`owned_meta::interned`] avoids
+//! allocating a new name for every event.
+
+use crossbeam_utils::sync::ShardedLock;
+#[cfg(feature = "json")]
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+// Interned names go in, but never come out.
+lazy_static! {
+    static ref INTERNED_NAMES: ShardedLock<HashSet<&'static str>> =
+        ShardedLock::new(HashSet::new());
+}
+
+/// Return a `'static` copy of `name`, allocating only the first time a given name is seen.
+/// Interned names are never freed, so only intern names that come from a bounded set,
+/// such as plugin or tenant names.
+pub fn intern(name: &str) -> &'static str {
+    if let Some(interned) = INTERNED_NAMES.read().unwrap().get(name) {
+        return interned;
+    }
+
+    let mut guard = INTERNED_NAMES.write().unwrap();
+
+    // Check again to see if it has already been interned before we got the write lock
+    if let Some(interned) = guard.get(name) {
+        return interned;
+    }
+
+    let interned: &'static str = Box::leak(name.to_string().into_boxed_str());
+    guard.insert(interned);
+    interned
+}
+
+/// Overrides for how a record is written, passed as the `_meta` key-value.
+/// Usually created with [`evt_meta!`](crate::evt_meta).
+#[allow(non_camel_case_types)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+#[derive(Debug, Default)]
+pub struct meta {
+    pub provider: &'static str,
+    pub event_name: &'static str,
+    pub keyword: u64,
+    /// The event opcode. See [`opcode`] for the well-known values.
+    pub opcode: u8,
+    /// A raw event level from 1 to 255, used instead of the level mapped from the record.
+    /// 0 keeps the mapped level.
+    pub level: u8,
+    /// A stable event ID. 0 means the event has no ID.
+    pub id: u16,
+    /// The version of the event schema identified by `id`.
+    pub version: u8,
+    /// The event tag. ETW keeps the low 28 bits and EventHeader keeps the low 16 bits,
+    /// and the higher bits are dropped without an error. Tags up to `0xFFFF` are kept
+    /// on both platforms.
+    pub tag: u32,
+}
+
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+impl log::kv::ToValue for meta {
+    fn to_value(&self) -> log::kv::Value<'_> {
+        #[cfg(feature = "kv_unstable_json")]
+        return log::kv::Value::capture_serde(self);
+        #[cfg(not(feature = "kv_unstable_json"))]
+        return log::kv::Value::capture_debug(self);
+    }
+}
+
+/// The same overrides as [`meta`], with provider and event names that are not `'static`.
+/// Pass it as the `_meta` key-value exactly like [`meta`].
+#[allow(non_camel_case_types)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+#[derive(Debug, Default)]
+pub struct owned_meta {
+    pub provider: Cow<'static, str>,
+    pub event_name: Cow<'static, str>,
+    pub keyword: u64,
+    /// The event opcode. See [`opcode`] for the well-known values.
+    pub opcode: u8,
+    /// A raw event level from 1 to 255. 0 keeps the level mapped from the record.
+    pub level: u8,
+    /// A stable event ID. 0 means the event has no ID.
+    pub id: u16,
+    /// The version of the event schema identified by `id`.
+    pub version: u8,
+    /// The event tag. ETW keeps the low 28 bits and EventHeader keeps the low 16 bits,
+    /// and the higher bits are dropped without an error. Tags up to `0xFFFF` are kept
+    /// on both platforms.
+    pub tag: u32,
+}
+
+impl owned_meta {
+    /// Create metadata with the given names and keyword.
+    pub fn new(
+        provider: impl Into<Cow<'static, str>>,
+        event_name: impl Into<Cow<'static, str>>,
+        keyword: u64,
+    ) -> Self {
+        owned_meta {
+            provider: provider.into(),
+            event_name: event_name.into(),
+            keyword,
+            ..Default::default()
+        }
+    }
+
+    /// Create metadata with [interned](intern) names, so that logging the same names
+    /// again does not allocate.
+    pub fn interned(provider: &str, event_name: &str, keyword: u64) -> Self {
+        Self::new(intern(provider), intern(event_name), keyword)
+    }
+}
+
+impl From<meta> for owned_meta {
+    fn from(meta: meta) -> Self {
+        owned_meta {
+            provider: Cow::Borrowed(meta.provider),
+            event_name: Cow::Borrowed(meta.event_name),
+            keyword: meta.keyword,
+            opcode: meta.opcode,
+            level: meta.level,
+            id: meta.id,
+            version: meta.version,
+            tag: meta.tag,
+        }
+    }
+}
+
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+impl log::kv::ToValue for owned_meta {
+    fn to_value(&self) -> log::kv::Value<'_> {
+        #[cfg(feature = "kv_unstable_json")]
+        return log::kv::Value::capture_serde(self);
+        #[cfg(not(feature = "kv_unstable_json"))]
+        return log::kv::Value::capture_debug(self);
+    }
+}
+
+/// Well-known values for [`meta::opcode`].
+pub mod opcode {
+    /// An informational event. This is the default.
+    pub const INFO: u8 = 0;
+    /// The start of an activity.
+    pub const START: u8 = 1;
+    /// The end of an activity.
+    pub const STOP: u8 = 2;
+}
+
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+pub(crate) use reserved::*;
+
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+mod reserved {
+    use super::{meta, owned_meta};
+    use crate::logger::{timestamp_from_value, EventOptions};
+    use log::kv::{Error, Key, Source, Value, Visitor};
+    use std::time::SystemTime;
+
+    const RESERVED_KEYS: [&str; 10] = [
+        "_meta",
+        "_provider",
+        "_event",
+        "_keyword",
+        "_level",
+        "_opcode",
+        "_id",
+        "_version",
+        "_tag",
+        "_timestamp",
+    ];
+
+    pub(crate) fn is_reserved_key(key: &str) -> bool {
+        key.starts_with('_') && RESERVED_KEYS.contains(&key)
+    }
+
+    /// The reserved key-values of a record, collected in a single pass.
+    #[derive(Default)]
+    pub(crate) struct ReservedKeys<'kvs> {
+        meta: Option<Value<'kvs>>,
+        provider: Option<Value<'kvs>>,
+        event: Option<Value<'kvs>>,
+        keyword: Option<Value<'kvs>>,
+        level: Option<Value<'kvs>>,
+        opcode: Option<Value<'kvs>>,
+        id: Option<Value<'kvs>>,
+        version: Option<Value<'kvs>>,
+        tag: Option<Value<'kvs>>,
+        timestamp: Option<Value<'kvs>>,
+    }
+
+    impl<'kvs> ReservedKeys<'kvs> {
+        pub(crate) fn new(source: &'kvs dyn Source) -> Self {
+            let mut keys = ReservedKeys::default();
+            let _ = source.visit(&mut keys);
+            keys
+        }
+
+        /// Apply the overrides to the defaults taken from the record.
+        pub(crate) fn apply<'a>(
+            &'a self,
+            provider_name: &mut &'a str,
+            options: &mut EventOptions<'a>,
+            timestamp: &mut SystemTime,
+        ) {
+            if let Some(meta) = self.meta.as_ref().and_then(MetaRef::from_value) {
+                *provider_name = meta.provider;
+                options.name = meta.event_name;
+                options.keyword = meta.keyword;
+                options.opcode = meta.opcode;
+                options.id = meta.id;
+                options.version = meta.version;
+                options.tag = meta.tag;
+                if meta.level != 0 {
+                    options.level = meta.level;
+                }
+            }
+
+            fn int<T: TryFrom<u64>>(value: &Option<Value>) -> Option<T> {
+                value
+                    .as_ref()
+                    .and_then(|v| v.to_u64())
+                    .and_then(|v| T::try_from(v).ok())
+            }
+
+            if let Some(provider) = self.provider.as_ref().and_then(|v| v.to_borrowed_str()) {
+                *provider_name = provider;
+            }
+            if let Some(event) = self.event.as_ref().and_then(|v| v.to_borrowed_str()) {
+                options.name = event;
+            }
+            if let Some(keyword) = int(&self.keyword) {
+                options.keyword = keyword;
+            }
+            if let Some(level) = int::<u8>(&self.level).filter(|level| *level != 0) {
+                options.level = level;
+            }
+            if let Some(opcode) = int(&self.opcode) {
+                options.opcode = opcode;
+            }
+            if let Some(id) = int(&self.id) {
+                options.id = id;
+            }
+            if let Some(version) = int(&self.version) {
+                options.version = version;
+            }
+            if let Some(tag) = int(&self.tag) {
+                options.tag = tag;
+            }
+            if let Some(original) = self.timestamp.as_ref().and_then(timestamp_from_value) {
+                *timestamp = original;
+            }
+        }
+    }
+
+    /// A `_meta` value of either the static or the owned form.
+    struct MetaRef<'a> {
+        provider: &'a str,
+        event_name: &'a str,
+        keyword: u64,
+        opcode: u8,
+        level: u8,
+        id: u16,
+        version: u8,
+        tag: u32,
+    }
+
+    impl<'a> MetaRef<'a> {
+        fn from_value(value: &'a Value) -> Option<Self> {
+            if let Some(meta) = value.downcast_ref::<meta>() {
+                Some(MetaRef {
+                    provider: meta.provider,
+                    event_name: meta.event_name,
+                    keyword: meta.keyword,
+                    opcode: meta.opcode,
+                    level: meta.level,
+                    id: meta.id,
+                    version: meta.version,
+                    tag: meta.tag,
+                })
+            } else {
+                value.downcast_ref::<owned_meta>().map(|meta| MetaRef {
+                    provider: &meta.provider,
+                    event_name: &meta.event_name,
+                    keyword: meta.keyword,
+                    opcode: meta.opcode,
+                    level: meta.level,
+                    id: meta.id,
+                    version: meta.version,
+                    tag: meta.tag,
+                })
+            }
+        }
+    }
+
+    impl<'kvs> Visitor<'kvs> for ReservedKeys<'kvs> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+            let slot = match key.as_str() {
+                "_meta" => &mut self.meta,
+                "_provider" => &mut self.provider,
+                "_event" => &mut self.event,
+                "_keyword" => &mut self.keyword,
+                "_level" => &mut self.level,
+                "_opcode" => &mut self.opcode,
+                "_id" => &mut self.id,
+                "_version" => &mut self.version,
+                "_tag" => &mut self.tag,
+                "_timestamp" => &mut self.timestamp,
+                _ => return Ok(()),
+            };
+            *slot = Some(value);
+            Ok(())
+        }
+    }
+
+    /// A [`Source`] adapter that hides the reserved key-values of a record.
+    pub(crate) struct WithoutReservedKeys<'a>(pub(crate) &'a dyn Source);
+
+    impl Source for WithoutReservedKeys<'_> {
+        fn visit<'kvs>(&'kvs self, visitor: &mut dyn Visitor<'kvs>) -> Result<(), Error> {
+            struct Filter<'a, 'kvs>(&'a mut dyn Visitor<'kvs>);
+
+            impl<'a, 'kvs> Visitor<'kvs> for Filter<'a, 'kvs> {
+                fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+                    if is_reserved_key(key.as_str()) {
+                        Ok(())
+                    } else {
+                        self.0.visit_pair(key, value)
+                    }
+                }
+            }
+
+            self.0.visit(&mut Filter(visitor))
+        }
+    }
+}
@@ -1,33 +1,60 @@
 #[macro_use]
 extern crate lazy_static;
 
+mod background;
+mod buffers;
 #[cfg(target_os = "windows")]
 mod etw;
+mod rate_limit;
+mod sinks;
 #[cfg(target_os = "linux")]
 mod user_events;
 
+pub mod clock;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod error;
+pub mod event;
 pub mod logger;
+pub mod static_events;
+pub mod typed_events;
 
-#[cfg(feature = "kv_unstable_json")]
-pub mod event {
-    use serde_derive::{Deserialize, Serialize};
+#[cfg(feature = "derive")]
+pub use log_etw_macros::LogEtwEvent;
+pub use typed_events::LogEtwEvent;
 
-    #[allow(non_camel_case_types)]
-    #[derive(Serialize, Deserialize)]
-    pub struct meta {
-        pub provider: &'static str,
-        pub event_name: &'static str,
-        pub keyword: u64,
-    }
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::buffers::RecordEncoder;
+    #[cfg(target_os = "linux")]
+    pub use eventheader;
+    pub use log;
+    pub use log_etw_macros::__static_event;
+    #[cfg(target_os = "windows")]
+    pub use tracelogging;
 }
 
+/// Create a `_meta` key-value that overrides the provider, event name and keyword of a record.
+/// Any of the other fields of [`event::meta`] can follow as `name: value` pairs.
+/// Requires the `kv_unstable` or `kv_unstable_json` feature.
+///
+/// ```ignore
+/// info!(_meta = evt_meta!("MyProvider", "RequestStarted", 0x2, opcode: log_etw::event::opcode::START, id: 100); "Started");
+/// ```
 #[macro_export]
 macro_rules! evt_meta {
-    ($provider:literal, $evtname:literal, $keyword:expr) => {
-        log::kv::Value::capture_serde(&crate::event::meta {
-            provider: $provider,
-            event_name: $evtname,
-            keyword: $keyword,
+    ($provider:literal, $evtname:literal, $keyword:expr $(, $field:ident: $value:expr)* $(,)?) => {
+        $crate::__private::log::kv::ToValue::to_value(&{
+            // The update is needless when every field is given
+            #[allow(clippy::needless_update)]
+            let meta = $crate::event::meta {
+                provider: $provider,
+                event_name: $evtname,
+                keyword: $keyword,
+                $($field: $value,)*
+                ..::core::default::Default::default()
+            };
+            meta
         })
     };
 }
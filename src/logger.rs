@@ -1,20 +1,277 @@
+use crate::background::{BackgroundWriter, QueuedRecord};
+use crate::clock::{Clock, SystemClock};
+use crate::error::Error;
+use crate::rate_limit::RateLimiter;
+use crate::sinks::{JsonFileSink, Sink, TextSink};
+use crate::typed_events::FieldFormat;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use crossbeam_utils::sync::ShardedLock;
 use log::Log;
 use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tracelogging::Guid;
 
-// Providers go in, but never come out.
-// On Windows this cannot be safely compiled into a dylib, since the providers will never be dropped.
+// Shared by the installed logger and typed events.
 lazy_static! {
-    static ref PROVIDER_CACHE: ShardedLock<HashMap<String, Pin<Arc<ProviderWrapper>>>> =
-        ShardedLock::new(HashMap::new());
+    static ref PROVIDER_CACHE: Arc<ProviderCache> = Arc::new(ProviderCache::new());
+}
+
+// Identifies each configuration of each logger in the callsite cache, which is shared by
+// every logger on a thread.
+static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+// The logger installed by `try_install`, so that `shutdown` can reach it from exit hooks.
+static INSTALLED_LOGGER: AtomicPtr<EtwEventHeaderLogger> = AtomicPtr::new(std::ptr::null_mut());
+
+// Held while installing a logger. True once `ForwardToInstalled` is the global logger.
+static INSTALLING: Mutex<bool> = Mutex::new(false);
+
+/// The global logger. Records are dropped until a logger has been installed and started.
+struct ForwardToInstalled;
+
+impl Log for ForwardToInstalled {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        installed_logger().map_or(false, |logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if let Some(logger) = installed_logger() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = installed_logger() {
+            logger.flush();
+        }
+    }
+}
+
+fn installed_logger() -> Option<&'static EtwEventHeaderLogger> {
+    // Installed loggers are never freed
+    unsafe { INSTALLED_LOGGER.load(Ordering::Acquire).as_ref() }
+}
+
+const CALLSITE_CACHE_SIZE: usize = 64;
+
+// A direct-mapped cache of resolved providers, indexed by the address of the provider name.
+// Targets are almost always string literals, so after the first call a lookup is a short
+// string comparison, with no hashing or locking. A name at another address is found by
+// searching the whole cache before falling back to the provider cache.
+thread_local! {
+    static CALLSITE_CACHE: RefCell<[Option<Rc<Callsite>>; CALLSITE_CACHE_SIZE]> =
+        RefCell::new(std::array::from_fn(|_| None));
+}
+
+/// Providers by name. Providers go in, and only come out when the cache is shut down.
+/// On Windows a dylib must shut down its loggers before it is unloaded, or the providers are never unregistered.
+pub(crate) struct ProviderCache {
+    providers: ShardedLock<HashMap<String, Pin<Arc<ProviderWrapper>>>>,
+    // Set under the write lock once the cached providers have been unregistered.
+    shut_down: AtomicBool,
+}
+
+impl ProviderCache {
+    fn new() -> Self {
+        ProviderCache {
+            providers: ShardedLock::new(HashMap::new()),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Look up a provider, creating and registering it if needed.
+    /// The provider ID defaults to one generated from the name, and the group defaults to none.
+    /// On Linux, a new provider registers a tracepoint for each of `event_sets`.
+    fn get_or_create(
+        &self,
+        provider_name: &str,
+        provider_id: Option<&Guid>,
+        provider_group: Option<&ProviderGroup>,
+        event_sets: &[(u8, u64)],
+    ) -> Pin<Arc<ProviderWrapper>> {
+        if let Some(provider) = self.get(provider_name) {
+            return provider;
+        }
+
+        let mut guard = self.providers.write().unwrap();
+
+        // A record that raced with shutdown gets a provider that is not registered or cached
+        if self.is_shut_down() {
+            return ProviderWrapper::unregistered(provider_name);
+        }
+
+        // Check again to see if it has already been created before we got the write lock
+        guard
+            .entry(provider_name.to_string())
+            .or_insert_with(|| {
+                ProviderWrapper::new(
+                    provider_name,
+                    &provider_id
+                        .copied()
+                        .unwrap_or_else(|| Guid::from_name(provider_name)),
+                    provider_group.unwrap_or(&ProviderGroup::Unset),
+                    event_sets,
+                )
+            })
+            .clone()
+    }
+
+    /// Look up a provider without creating it.
+    fn get(&self, provider_name: &str) -> Option<Pin<Arc<ProviderWrapper>>> {
+        self.providers.read().unwrap().get(provider_name).cloned()
+    }
+
+    /// The counters of every cached provider, by name.
+    fn stats(&self) -> Vec<ProviderStats> {
+        let mut stats: Vec<_> = self
+            .providers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, provider)| provider.counters.snapshot(name))
+            .collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+        stats
+    }
+
+    fn is_shut_down(&self) -> bool {
+        self.shut_down.load(Ordering::Acquire)
+    }
+
+    /// Unregister and forget every cached provider.
+    fn shutdown(&self) {
+        let providers: Vec<_> = {
+            let mut guard = self.providers.write().unwrap();
+            self.shut_down.store(true, Ordering::Release);
+            guard.drain().map(|(_, provider)| provider).collect()
+        };
+
+        // Threads may still hold the providers in their callsite caches,
+        // but once unregistered, nothing they write goes anywhere.
+        for provider in providers {
+            provider.unregister();
+        }
+    }
+}
+
+/// A level, keyword and the event set registered for them, if any.
+#[cfg(target_os = "linux")]
+type CachedEventSet = (u8, u64, Option<Arc<eventheader_dynamic::EventSet>>);
+
+/// A provider resolved for one provider name, along with the event sets looked up with it so far.
+/// On Linux, a level and keyword without a registered event set is remembered as `None`.
+pub(crate) struct Callsite {
+    generation: usize,
+    name_ptr: usize,
+    name: Box<str>,
+    pub(crate) provider: Pin<Arc<ProviderWrapper>>,
+    #[cfg(target_os = "linux")]
+    event_sets: RefCell<Vec<CachedEventSet>>,
+}
+
+impl Callsite {
+    fn new(generation: usize, name: &str, provider: Pin<Arc<ProviderWrapper>>) -> Self {
+        Callsite {
+            generation,
+            name_ptr: name.as_ptr() as usize,
+            name: name.into(),
+            provider,
+            #[cfg(target_os = "linux")]
+            event_sets: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn matches(&self, generation: usize, name: &str) -> bool {
+        self.generation == generation && *self.name == *name
+    }
+
+    /// The event set for a level and keyword. If it has not been registered yet,
+    /// it is registered now if it was declared or the registration policy allows it.
+    /// The answer is cached either way, so a set the policy excludes only takes the lock once.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn event_set(
+        &self,
+        level: u8,
+        keyword: u64,
+        exporter_config: &ExporterConfig,
+    ) -> Option<Arc<eventheader_dynamic::EventSet>> {
+        if let Some((_, _, es)) = self
+            .event_sets
+            .borrow()
+            .iter()
+            .find(|(l, k, _)| *l == level && *k == keyword)
+        {
+            return es.clone();
+        }
+
+        let provider = self.provider.as_ref();
+        let es = match provider.find_set(level.into(), keyword) {
+            Some(es) => Some(es),
+            None if exporter_config.registration_policy == RegistrationPolicy::Lazy
+                || exporter_config.event_sets.contains(&(level, keyword)) =>
+            {
+                Some(provider.register_set(level.into(), keyword))
+            }
+            None => None,
+        };
+
+        self.event_sets
+            .borrow_mut()
+            .push((level, keyword, es.clone()));
+        es
+    }
+
+    /// Whether a session is listening for a level and keyword. On Linux, this never registers
+    /// an event set: one that would be registered by the first record counts as enabled,
+    /// so that the record is logged and registers it.
+    pub(crate) fn enabled(
+        &self,
+        level: u8,
+        keyword: u64,
+        #[cfg_attr(target_os = "windows", allow(unused_variables))]
+        exporter_config: &ExporterConfig,
+    ) -> bool {
+        #[cfg(target_os = "windows")]
+        return self.provider.enabled(level, keyword);
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some((_, _, es)) = self
+                .event_sets
+                .borrow()
+                .iter()
+                .find(|(l, k, _)| *l == level && *k == keyword)
+            {
+                return es.as_ref().map_or(false, |es| es.enabled());
+            }
+
+            match self.provider.as_ref().find_set(level.into(), keyword) {
+                Some(es) => {
+                    let enabled = es.enabled();
+                    self.event_sets
+                        .borrow_mut()
+                        .push((level, keyword, Some(es)));
+                    enabled
+                }
+                None => {
+                    exporter_config.registration_policy == RegistrationPolicy::Lazy
+                        || exporter_config.event_sets.contains(&(level, keyword))
+                }
+            }
+        }
+    }
 }
 
 pub(crate) struct ExporterConfig {
+    // Unique to this configuration, so that callsites resolved under another one are not reused
+    pub(crate) generation: usize,
     pub(crate) default_provider_name: String,
     pub(crate) default_provider_id: Guid,
     pub(crate) default_provider_group: ProviderGroup,
@@ -22,52 +279,212 @@ pub(crate) struct ExporterConfig {
     #[allow(dead_code)]
     pub(crate) json: bool,
     pub(crate) common_schema: bool,
+    pub(crate) timestamp_format: TimestampFormat,
+    pub(crate) clock: Box<dyn Clock>,
+    pub(crate) field_set: FieldSet,
+    pub(crate) event_sets: Vec<(u8, u64)>,
+    pub(crate) registration_policy: RegistrationPolicy,
+    pub(crate) providers: Vec<String>,
+    pub(crate) routes: Vec<(String, String)>,
+    pub(crate) keyword_map: Vec<(String, u64)>,
+    pub(crate) max_level: log::LevelFilter,
+    pub(crate) stats_interval: Option<Duration>,
+    pub(crate) rate_limit: Option<RateLimit>,
+    pub(crate) fallback: Fallback,
+    pub(crate) native_events: bool,
+    // Also in `sinks`. Kept so that reconfiguring with the same file keeps writing through it.
+    pub(crate) json_file: Option<Arc<JsonFileSink>>,
+    pub(crate) sinks: Vec<Box<dyn Sink>>,
+}
+
+impl ExporterConfig {
+    /// The provider for records with this target: that of the first route whose prefix
+    /// the target starts with, or the target itself.
+    pub(crate) fn route<'a>(&'a self, target: &'a str) -> &'a str {
+        self.routes
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map_or(target, |(_, provider_name)| provider_name)
+    }
+
+    /// The keyword for records with this target: that of the first entry of the keyword map
+    /// whose prefix the target starts with, or 1.
+    pub(crate) fn keyword(&self, target: &str) -> u64 {
+        self.keyword_map
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map_or(1, |(_, keyword)| *keyword)
+    }
+
+    /// Call `f` with the provider, event options and timestamp of a record: its routed target,
+    /// mapped keyword and the time it was logged, overridden by any reserved key-values.
+    /// They are passed to `f` rather than returned, since they can borrow from the key-values.
+    pub(crate) fn resolve<R>(
+        &self,
+        record: &log::Record,
+        timestamp: SystemTime,
+        f: impl FnOnce(&str, &EventOptions, SystemTime) -> R,
+    ) -> R {
+        let provider_name = self.route(record.target());
+        let options = EventOptions {
+            keyword: self.keyword(record.target()),
+            ..EventOptions::new(record.level())
+        };
+
+        #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+        let reserved_keys = crate::event::ReservedKeys::new(record.key_values());
+        #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+        let (provider_name, options, timestamp) = {
+            let (mut provider_name, mut options, mut timestamp) =
+                (provider_name, options, timestamp);
+            reserved_keys.apply(&mut provider_name, &mut options, &mut timestamp);
+            (provider_name, options, timestamp)
+        };
+
+        f(provider_name, &options, timestamp)
+    }
 }
 
 pub(crate) struct ProviderWrapper {
-    #[cfg(any(target_os = "windows"))]
+    #[cfg(target_os = "windows")]
     provider: tracelogging_dynamic::Provider,
-    #[cfg(any(target_os = "linux"))]
+    #[cfg(target_os = "linux")]
     provider: std::sync::RwLock<eventheader_dynamic::Provider>,
+    #[cfg(target_os = "windows")]
+    register_result: AtomicU32,
+    pub(crate) counters: ProviderCounters,
+}
+
+// The error `EventBuilder::write` returns for events larger than 64KB.
+#[cfg(target_os = "windows")]
+const EVENT_TOO_LARGE: u32 = 534; // ERROR_ARITHMETIC_OVERFLOW
+#[cfg(target_os = "linux")]
+const EVENT_TOO_LARGE: u32 = 34; // ERANGE
+
+/// Counts what happened to the events sent to one provider.
+#[derive(Default)]
+pub(crate) struct ProviderCounters {
+    attempted: AtomicU64,
+    written: AtomicU64,
+    disabled: AtomicU64,
+    failed: AtomicU64,
+    truncated: AtomicU64,
+    last_error: AtomicU32,
+}
+
+impl ProviderCounters {
+    /// An event was dropped because no session is listening for it.
+    pub(crate) fn disabled(&self) {
+        self.attempted.fetch_add(1, Ordering::Relaxed);
+        self.disabled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An event was written, with this result from `EventBuilder::write`.
+    pub(crate) fn write_result(&self, result: u32) {
+        self.attempted.fetch_add(1, Ordering::Relaxed);
+        let counter = match result {
+            0 => &self.written,
+            EVENT_TOO_LARGE => &self.truncated,
+            _ => &self.failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if result != 0 {
+            self.last_error.store(result, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self, name: &str) -> ProviderStats {
+        ProviderStats {
+            name: name.to_owned(),
+            attempted: self.attempted.load(Ordering::Relaxed),
+            written: self.written.load(Ordering::Relaxed),
+            disabled: self.disabled.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            truncated: self.truncated.load(Ordering::Relaxed),
+            last_error: self.last_error.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What happened to the events sent to one provider since it was created.
+/// Returned by [`LoggerHandle::stats`].
+///
+/// `attempted` is the sum of the other counts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProviderStats {
+    /// The provider name.
+    pub name: String,
+    /// Records and typed events sent to the provider.
+    pub attempted: u64,
+    /// Events written successfully.
+    pub written: u64,
+    /// Events dropped because no session was listening for their level and keyword.
+    pub disabled: u64,
+    /// Events that the operating system failed to write.
+    pub failed: u64,
+    /// Events dropped because they were larger than the 64KB limit.
+    pub truncated: u64,
+    /// The most recent error code, if any writes have failed: a Win32 error on Windows,
+    /// or an `errno` value such as `EBADF` on Linux.
+    pub last_error: u32,
 }
 
 impl ProviderWrapper {
+    /// On Linux, each event set is checked instead.
+    #[cfg(target_os = "windows")]
     pub(crate) fn enabled(&self, level: u8, keyword: u64) -> bool {
-        #[cfg(any(target_os = "windows"))]
-        return self.provider.enabled(level.into(), keyword);
+        self.provider.enabled(level.into(), keyword)
+    }
 
-        #[cfg(any(target_os = "linux"))]
-        {
-            let es = self
-                .provider
-                .read()
-                .unwrap()
-                .find_set(level.into(), keyword);
-            if es.is_some() {
-                es.unwrap().enabled()
-            } else {
-                false
-            }
-        }
+    /// The error from registering the provider, or 0 if it was registered.
+    /// On Linux, this is the error from registering the first of `event_sets`, such as
+    /// `EOPNOTSUPP` when user_events is not available. It is 0 if none are declared.
+    pub(crate) fn registration_error(
+        &self,
+        #[cfg_attr(target_os = "windows", allow(unused_variables))] event_sets: &[(u8, u64)],
+    ) -> u32 {
+        #[cfg(target_os = "windows")]
+        return self.register_result.load(Ordering::Relaxed);
+
+        #[cfg(target_os = "linux")]
+        event_sets
+            .first()
+            .and_then(|(level, keyword)| {
+                self.provider
+                    .read()
+                    .unwrap()
+                    .find_set((*level).into(), *keyword)
+            })
+            .map_or(0, |es| es.errno() as u32)
+    }
+
+    /// Unregister the provider and all of its tracepoints. Later writes are dropped.
+    pub(crate) fn unregister(&self) {
+        #[cfg(target_os = "windows")]
+        self.provider.unregister();
+
+        #[cfg(target_os = "linux")]
+        self.provider.write().unwrap().unregister();
     }
 
-    #[cfg(any(target_os = "windows"))]
+    #[cfg(target_os = "windows")]
     pub(crate) fn get_provider(self: Pin<&Self>) -> Pin<&tracelogging_dynamic::Provider> {
         unsafe { self.map_unchecked(|s| &s.provider) }
     }
 
-    #[cfg(any(target_os = "linux"))]
+    #[cfg(target_os = "linux")]
     pub(crate) fn get_provider(
         self: Pin<&Self>,
     ) -> Pin<&std::sync::RwLock<eventheader_dynamic::Provider>> {
         unsafe { self.map_unchecked(|s| &s.provider) }
     }
 
-    #[cfg(all(target_os = "windows"))]
+    #[cfg(target_os = "windows")]
     pub(crate) fn new(
         provider_name: &str,
         provider_id: &Guid,
         provider_group: &ProviderGroup,
+        _: &[(u8, u64)],
     ) -> Pin<Arc<Self>> {
         let mut options = tracelogging_dynamic::Provider::options();
         if let ProviderGroup::Windows(guid) = provider_group {
@@ -80,35 +497,61 @@ impl ProviderWrapper {
                 &options,
                 provider_id,
             ),
+            register_result: AtomicU32::new(0),
+            counters: ProviderCounters::default(),
         });
-        unsafe {
-            wrapper.as_ref().get_provider().register();
-        }
+        let result = unsafe { wrapper.as_ref().get_provider().register() };
+        wrapper.register_result.store(result, Ordering::Relaxed);
 
         wrapper
     }
 
-    #[cfg(all(target_os = "linux"))]
+    /// A provider that is never registered, so everything written to it is dropped.
+    #[cfg(target_os = "windows")]
+    fn unregistered(provider_name: &str) -> Pin<Arc<Self>> {
+        Arc::pin(ProviderWrapper {
+            provider: tracelogging_dynamic::Provider::new(
+                provider_name,
+                &tracelogging_dynamic::Provider::options(),
+            ),
+            register_result: AtomicU32::new(0),
+            counters: ProviderCounters::default(),
+        })
+    }
+
+    /// A provider without tracepoints. Any that a record registers lazily are
+    /// unregistered when the provider is dropped.
+    #[cfg(target_os = "linux")]
+    fn unregistered(provider_name: &str) -> Pin<Arc<Self>> {
+        Self::new(
+            provider_name,
+            &Guid::from_name(provider_name),
+            &ProviderGroup::Unset,
+            &[],
+        )
+    }
+
+    #[cfg(target_os = "linux")]
     pub(crate) fn new(
         provider_name: &str,
         _: &Guid,
         provider_group: &ProviderGroup,
+        event_sets: &[(u8, u64)],
     ) -> Pin<Arc<Self>> {
         let mut options = eventheader_dynamic::Provider::new_options();
         if let ProviderGroup::Linux(ref name) = provider_group {
-            options = *options.group_name(&name);
+            options = *options.group_name(name);
         }
         let mut provider = eventheader_dynamic::Provider::new(provider_name, &options);
 
-        for lvl in log::Level::iter() {
-            provider.register_set(map_level(lvl).into(), 1);
+        // Register every declared tracepoint up front, so they can be enabled before first use
+        for (level, keyword) in event_sets {
+            provider.register_set((*level).into(), *keyword);
         }
 
         Arc::pin(ProviderWrapper {
-            provider: std::sync::RwLock::new(eventheader_dynamic::Provider::new(
-                provider_name,
-                &options,
-            )),
+            provider: std::sync::RwLock::new(provider),
+            counters: ProviderCounters::default(),
         })
     }
 }
@@ -122,17 +565,530 @@ pub(crate) enum ProviderGroup {
     Linux(Cow<'static, str>),
 }
 
+impl ProviderGroup {
+    /// Parse a group from configuration: a GUID on Windows, or a group name on Linux.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        if cfg!(target_os = "windows") {
+            Guid::try_parse(value)
+                .map(ProviderGroup::Windows)
+                .ok_or_else(|| format!("{:?} is not a GUID", value))
+        } else {
+            Ok(ProviderGroup::Linux(Cow::Owned(value.to_owned())))
+        }
+    }
+}
+
+/// How the `time` field of regular (non-Common Schema) events is encoded.
+/// Every format except the default [`TimestampFormat::SystemTime`] is encoded the same way
+/// on Windows and Linux. The default keeps milliseconds on Windows but only whole seconds
+/// on Linux, so use [`TimestampFormat::Rfc3339`] or [`TimestampFormat::UnixNanos`] for
+/// nanosecond precision on both.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum TimestampFormat {
+    /// 100ns ticks since 1601-01-01 UTC, the same representation as a Windows FILETIME.
+    /// ETW decodes this as a UTC date and time; EventHeader decodes it as an integer.
+    FileTime,
+    /// Nanoseconds since the Unix epoch.
+    UnixNanos,
+    /// An RFC 3339 string with nanosecond precision.
+    Rfc3339,
+    /// The platform's own date and time field, which decoders show as a time: a Windows
+    /// SYSTEMTIME with millisecond precision, or on Linux, where EventHeader has no
+    /// equivalent, whole seconds since the Unix epoch.
+    #[default]
+    SystemTime,
+    /// Do not add a `time` field. Consumers should use the timestamp that ETW
+    /// or the kernel records when the event is written.
+    None,
+}
+
+/// The standard fields added to each regular (non-Common Schema) event.
+/// Key-values from the log record are always added.
+///
+/// Source location strings add a significant number of bytes to every event,
+/// so high-volume providers may want to turn them off.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(default, deny_unknown_fields))]
+pub struct FieldSet {
+    /// `time`: the event timestamp, encoded as set by [`ExporterBuilder::with_timestamp_format`].
+    pub time: bool,
+    /// `Payload`: the formatted log message.
+    pub payload: bool,
+    /// `Module Path`: the module that logged the record.
+    pub module_path: bool,
+    /// `File`: the source file that logged the record.
+    pub file: bool,
+    /// `Line`: the source line that logged the record.
+    pub line: bool,
+    /// `Target`: the log target. Usually the same as the provider name.
+    pub target: bool,
+    /// `Level`: the log level as text, such as `WARN`.
+    pub level: bool,
+    /// `Thread ID`: the operating system ID of the logging thread.
+    pub thread_id: bool,
+    /// `Thread Name`: the name of the logging thread, if it has one.
+    pub thread_name: bool,
+    /// `Process ID`: the operating system ID of the logging process.
+    pub process_id: bool,
+}
+
+impl FieldSet {
+    /// Only the timestamp and the formatted message.
+    pub const fn minimal() -> Self {
+        FieldSet {
+            time: true,
+            payload: true,
+            module_path: false,
+            file: false,
+            line: false,
+            target: false,
+            level: false,
+            thread_id: false,
+            thread_name: false,
+            process_id: false,
+        }
+    }
+}
+
+impl Default for FieldSet {
+    /// The timestamp, the formatted message and the source location.
+    fn default() -> Self {
+        FieldSet {
+            module_path: true,
+            file: true,
+            line: true,
+            ..Self::minimal()
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn os_thread_id() -> u32 {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThreadId() -> u32;
+    }
+
+    unsafe { GetCurrentThreadId() }
+}
+
+#[cfg(target_os = "linux")]
+fn os_thread_id() -> u32 {
+    unsafe { libc::gettid() as u32 }
+}
+
+thread_local! {
+    // Set while the background writer writes a record that was logged on another thread
+    static RECORD_THREAD: RefCell<Option<RecordThread>> = const { RefCell::new(None) };
+}
+
+/// What events record about the thread that logged them, captured so that the record
+/// can be written on another thread.
+pub(crate) struct RecordThread {
+    id: u32,
+    name: Option<String>,
+    #[cfg(feature = "spans")]
+    span_ids: Option<([u8; 16], [u8; 32])>,
+}
+
+impl RecordThread {
+    /// Capture the current thread, leaving out what `config` does not write.
+    pub(crate) fn capture(config: &ExporterConfig) -> Self {
+        RecordThread {
+            id: current_thread_id(),
+            name: if config.field_set.thread_name {
+                std::thread::current().name().map(str::to_owned)
+            } else {
+                None
+            },
+            #[cfg(feature = "spans")]
+            span_ids: if config.common_schema {
+                Some(active_span_ids())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Run `f` as if on the captured thread. The override is cleared even if `f` panics.
+    pub(crate) fn scope(self, f: impl FnOnce()) {
+        struct Restore;
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let _ = RECORD_THREAD.try_with(|thread| thread.borrow_mut().take());
+            }
+        }
+
+        let _ = RECORD_THREAD.try_with(|thread| *thread.borrow_mut() = Some(self));
+        let _restore = Restore;
+        f();
+    }
+}
+
+/// The ID of the thread that logged the record being written.
+pub(crate) fn current_thread_id() -> u32 {
+    RECORD_THREAD
+        .try_with(|thread| thread.borrow().as_ref().map(|thread| thread.id))
+        .ok()
+        .flatten()
+        .unwrap_or_else(os_thread_id)
+}
+
+/// Call `f` with the name of the thread that logged the record being written.
+pub(crate) fn with_thread_name<R>(f: impl FnOnce(Option<&str>) -> R) -> R {
+    let captured = RECORD_THREAD
+        .try_with(|thread| thread.borrow().as_ref().map(|thread| thread.name.clone()))
+        .ok()
+        .flatten();
+    match captured {
+        Some(name) => f(name.as_deref()),
+        None => f(std::thread::current().name()),
+    }
+}
+
+/// The OpenTelemetry span ID and trace ID that were active when the record being written was
+/// logged, as hex strings, or zeros if there was no active span.
+#[cfg(feature = "spans")]
+pub(crate) fn active_span_ids() -> ([u8; 16], [u8; 32]) {
+    use std::io::Write;
+
+    let captured = RECORD_THREAD
+        .try_with(|thread| {
+            thread
+                .borrow()
+                .as_ref()
+                .map(|thread| thread.span_ids.unwrap_or(([0; 16], [0; 32])))
+        })
+        .ok()
+        .flatten();
+    if let Some(ids) = captured {
+        return ids;
+    }
+
+    opentelemetry_api::trace::get_active_span(|span| {
+        if span.span_context().span_id() != opentelemetry_api::trace::SpanId::INVALID {
+            let trace_id = unsafe {
+                let mut trace_id = std::mem::MaybeUninit::<[u8; 32]>::uninit();
+                let mut cur = std::io::Cursor::new((&mut *trace_id.as_mut_ptr()).as_mut_slice());
+                write!(&mut cur, "{:32x}", span.span_context().trace_id()).expect("!write");
+                trace_id.assume_init()
+            };
+
+            let span_id = unsafe {
+                let mut span_id = std::mem::MaybeUninit::<[u8; 16]>::uninit();
+                let mut cur = std::io::Cursor::new((&mut *span_id.as_mut_ptr()).as_mut_slice());
+                write!(&mut cur, "{:16x}", span.span_context().span_id()).expect("!write");
+                span_id.assume_init()
+            };
+
+            (span_id, trace_id)
+        } else {
+            ([0; 16], [0; 32])
+        }
+    })
+}
+
+const UNIX_EPOCH_AS_FILETIME: i64 = 116_444_736_000_000_000;
+
+/// Nanoseconds since the Unix epoch, saturating at zero for earlier times.
+pub(crate) fn unix_nanos(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// 100ns ticks since 1601-01-01 UTC.
+pub(crate) fn filetime(timestamp: SystemTime) -> i64 {
+    match timestamp.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => UNIX_EPOCH_AS_FILETIME + (d.as_nanos() / 100) as i64,
+        Err(e) => UNIX_EPOCH_AS_FILETIME - (e.duration().as_nanos() / 100) as i64,
+    }
+}
+
+/// Format a timestamp as RFC 3339 with nanosecond precision into `buffer`, replacing its contents.
+pub(crate) fn format_rfc3339(buffer: &mut Vec<u8>, timestamp: SystemTime) -> &[u8] {
+    format_date_time(buffer, chrono::DateTime::from(timestamp))
+}
+
+fn format_date_time(buffer: &mut Vec<u8>, time: chrono::DateTime<chrono::Utc>) -> &[u8] {
+    use chrono::{Datelike, Timelike};
+    use std::io::Write;
+
+    buffer.clear();
+    let _ = write!(
+        buffer,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        time.year(),
+        time.month(),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        // Leap seconds are counted in the nanoseconds, past 999,999,999
+        time.nanosecond().min(999_999_999)
+    );
+    buffer
+}
+
+/// The message of a record. Static messages are used as-is, and anything else
+/// is formatted into `buffer`, replacing its contents.
+pub(crate) fn format_message<'a>(args: &std::fmt::Arguments, buffer: &'a mut Vec<u8>) -> &'a [u8] {
+    use std::io::Write;
+
+    if let Some(message) = args.as_str() {
+        message.as_bytes()
+    } else {
+        buffer.clear();
+        let _ = buffer.write_fmt(*args);
+        buffer
+    }
+}
+
 pub struct ExporterBuilder {
     pub(crate) provider_name: String,
     pub(crate) provider_id: Guid,
     pub(crate) provider_group: ProviderGroup,
     pub(crate) json: bool,
     pub(crate) emit_common_schema_events: bool,
+    pub(crate) timestamp_format: TimestampFormat,
+    pub(crate) clock: Box<dyn Clock>,
+    pub(crate) field_set: FieldSet,
+    pub(crate) event_sets: Vec<(u8, u64)>,
+    pub(crate) registration_policy: RegistrationPolicy,
+    pub(crate) providers: Vec<String>,
+    pub(crate) routes: Vec<(String, String)>,
+    pub(crate) keyword_map: Vec<(String, u64)>,
+    pub(crate) max_level: log::LevelFilter,
+    pub(crate) stats_interval: Option<Duration>,
+    pub(crate) fallback: Fallback,
+    pub(crate) console: Option<ConsoleFormat>,
+    pub(crate) console_colors: bool,
+    pub(crate) native_events: bool,
+    pub(crate) json_file: Option<(PathBuf, FileRotation)>,
+    pub(crate) background: Option<(usize, OverflowPolicy)>,
+    pub(crate) rate_limit: Option<RateLimit>,
+}
+
+/// A provider that was registered by [`ExporterBuilder::install`].
+#[derive(Clone, Debug)]
+pub struct RegisteredProvider {
+    /// The provider name.
+    pub name: String,
+    /// The ETW provider ID.
+    pub id: Guid,
+    /// The user_events tracepoints registered for the provider, such as `MyProvider_L4K1`.
+    /// Always empty on Windows.
+    pub tracepoints: Vec<String>,
+}
+
+/// Whether the native backend, ETW or user_events, can write events.
+/// Returned by [`LoggerHandle::status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendStatus {
+    /// The default provider was registered.
+    Available,
+    /// The default provider could not be registered, so no session can see its events.
+    /// On Linux this usually means that user_events is not enabled in the kernel,
+    /// or that `/sys/kernel/tracing/user_events_data` is not accessible to the process.
+    Unavailable {
+        /// The registration error: a Win32 error on Windows, or an `errno` value on Linux.
+        error: u32,
+    },
+}
+
+/// Where records go when the native backend is [unavailable](BackendStatus::Unavailable).
+/// Set with [`ExporterBuilder::with_fallback`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum Fallback {
+    /// Drop records, as if no session were listening for them.
+    #[default]
+    Silent,
+    /// Write records to stderr as lines of text.
+    Stderr,
+    /// Append records to a file as lines of text, creating it if needed.
+    File(PathBuf),
+}
+
+/// How [`ExporterBuilder::with_console`] lays out each event.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum ConsoleFormat {
+    /// One line of text per event: the timestamp, level, provider and event name, keyword
+    /// and message, followed by each other field as `name=value`.
+    #[default]
+    Compact,
+    /// One JSON object per line, laid out like the output of the `decode-perf` tool
+    /// from the `eventheader` crate, with the timestamp and event descriptor under `meta`.
+    Json,
+}
+
+/// When [`ExporterBuilder::with_json_file`] starts a new file, and how many old ones it keeps.
+///
+/// The current file is renamed to `<path>.1` when it is rotated, after renaming the previous
+/// `<path>.1` to `<path>.2` and so on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileRotation {
+    /// Rotate the file before a line would make it larger than this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate the file once it has been open this long.
+    /// Files are reopened, and their age starts over, when the process restarts.
+    pub max_age: Option<Duration>,
+    /// How many rotated files to keep. Older files are deleted.
+    pub keep: usize,
+}
+
+impl FileRotation {
+    /// Never rotate the file.
+    pub const fn never() -> Self {
+        FileRotation {
+            max_bytes: None,
+            max_age: None,
+            keep: 0,
+        }
+    }
+}
+
+impl Default for FileRotation {
+    /// Rotate the file at 10MB, keeping 5 old files.
+    fn default() -> Self {
+        FileRotation {
+            max_bytes: Some(10 * 1024 * 1024),
+            max_age: None,
+            keep: 5,
+        }
+    }
+}
+
+/// What [`ExporterBuilder::with_background_writer`] does with a record when its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum OverflowPolicy {
+    /// Drop the record.
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued record to make room.
+    DropOldest,
+    /// Wait for the background thread to make room.
+    Block,
+}
+
+/// The counters of a logger's background writer. Returned by [`LoggerHandle::queue_stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Records added to the queue.
+    pub queued: u64,
+    /// Queued records that the background thread has written.
+    pub written: u64,
+    /// Records dropped because the queue was full, by either [`OverflowPolicy::DropNewest`]
+    /// or [`OverflowPolicy::DropOldest`].
+    pub dropped: u64,
+    /// How many times a logging thread waited for room in the queue,
+    /// with [`OverflowPolicy::Block`].
+    pub blocked: u64,
+    /// Queued records that were dropped because writing them panicked.
+    pub panicked: u64,
+    /// Whether the background thread is still writing records. Once it is not, for example
+    /// after [`LoggerHandle::shutdown`], records that do not fit in the queue are dropped
+    /// even with [`OverflowPolicy::Block`].
+    pub running: bool,
+}
+
+/// What each bucket of [`ExporterBuilder::with_rate_limit`] limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum RateLimitScope {
+    /// The records written to each provider.
+    Provider,
+    /// The records written to each provider with each event name.
+    #[default]
+    EventName,
+    /// The records logged by each `log!` call, identified by its target, file and line.
+    Callsite,
+}
+
+/// A token bucket limit on the records written by [`ExporterBuilder::with_rate_limit`].
+///
+/// Each bucket starts with `burst` tokens and earns one more every `refill`, up to `burst`.
+/// Writing a record takes a token, and records logged while the bucket is empty are suppressed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// What each bucket limits.
+    pub scope: RateLimitScope,
+    /// How many records can be written at once after a quiet period.
+    pub burst: u32,
+    /// How long it takes to earn a token. Zero means no limit.
+    pub refill: Duration,
+    /// How long after a record is first suppressed the `LogEtwSuppressed` events are due.
+    /// They are written by the next record logged, or by [`Log::flush`].
+    pub summary_interval: Duration,
+}
+
+impl RateLimit {
+    /// Allow `per_second` records a second for each event name, in bursts of up to `burst`.
+    pub fn per_second(per_second: u32, burst: u32) -> Self {
+        RateLimit {
+            refill: Duration::from_secs(1)
+                .checked_div(per_second)
+                .unwrap_or(Duration::ZERO),
+            burst,
+            ..RateLimit::default()
+        }
+    }
+}
+
+impl Default for RateLimit {
+    /// Allow 100 records a second for each event name, in bursts of up to 1000,
+    /// and summarize what was suppressed after a minute.
+    fn default() -> Self {
+        RateLimit {
+            scope: RateLimitScope::EventName,
+            burst: 1000,
+            refill: Duration::from_millis(10),
+            summary_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What to do when a record uses a level and keyword combination that was not declared
+/// with [`ExporterBuilder::with_keywords`] or [`ExporterBuilder::with_event_set`].
+/// Typed events follow the policy of the installed logger, and are registered lazily
+/// if there is none.
+///
+/// This only matters on Linux, where each level and keyword combination is a separate
+/// tracepoint that must be registered before a session can enable it. ETW providers
+/// accept any level and keyword.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde_derive::Deserialize))]
+#[cfg_attr(feature = "config", serde(rename_all = "snake_case"))]
+pub enum RegistrationPolicy {
+    /// Register the tracepoint the first time a record uses it. Sessions can only enable
+    /// the tracepoint after that, so the first events written to it are never seen.
+    #[default]
+    Lazy,
+    /// Drop records whose tracepoint was not declared.
+    DeclaredOnly,
+}
+
+/// The level and keyword combinations registered for every provider by default:
+/// each log level with keyword 1, the keyword used by records without metadata.
+fn default_event_sets() -> Vec<(u8, u64)> {
+    log::Level::iter().map(|lvl| (map_level(lvl), 1)).collect()
 }
 
 /// Create an exporter builder. After configuring the builder,
 /// call [`ExporterBuilder::install`] to set it as the
-/// [global tracer provider](https://docs.rs/opentelemetry_api/latest/opentelemetry_api/global/index.html).
+/// [global logger](https://docs.rs/log/latest/log/fn.set_logger.html),
+/// or [`ExporterBuilder::build`] to create a logger that is not installed.
 pub fn new_logger(name: &str) -> ExporterBuilder {
     ExporterBuilder {
         provider_name: name.to_owned(),
@@ -140,6 +1096,23 @@ pub fn new_logger(name: &str) -> ExporterBuilder {
         provider_group: ProviderGroup::Unset,
         json: false,
         emit_common_schema_events: false,
+        timestamp_format: TimestampFormat::default(),
+        clock: Box::new(SystemClock),
+        field_set: FieldSet::default(),
+        event_sets: default_event_sets(),
+        registration_policy: RegistrationPolicy::default(),
+        providers: Vec::new(),
+        routes: Vec::new(),
+        keyword_map: Vec::new(),
+        max_level: log::LevelFilter::Trace,
+        stats_interval: None,
+        fallback: Fallback::default(),
+        console: None,
+        console_colors: false,
+        native_events: true,
+        json_file: None,
+        background: None,
+        rate_limit: None,
     }
 }
 
@@ -159,9 +1132,9 @@ impl ExporterBuilder {
         self.provider_id
     }
 
-    /// Override the default keywords and levels for events.
-    /// Provide an implementation of the [`KeywordLevelProvider`] trait that will
-    /// return the desired keywords and level values for each type of event.
+    // Override the default keywords and levels for events.
+    // Provide an implementation of the [`KeywordLevelProvider`] trait that will
+    // return the desired keywords and level values for each type of event.
     // pub fn with_custom_keywords_levels(
     //     mut self,
     //     config: impl KeywordLevelProvider + 'static,
@@ -176,7 +1149,7 @@ impl ExporterBuilder {
     /// the textual representation of the event payload should be left to the event
     /// consumer.
     /// Requires the `json` feature to be enabled on the crate.
-    #[cfg(any(feature = "json"))]
+    #[cfg(feature = "json")]
     #[cfg_attr(docsrs, doc(cfg(feature = "json")))]
     pub fn with_json_payload(mut self) -> Self {
         self.json = true;
@@ -197,185 +1170,2349 @@ impl ExporterBuilder {
         self
     }
 
-    /// For advanced scenarios.
-    /// Set the ETW provider group to join this provider to.
-    #[cfg(any(target_os = "windows", doc))]
-    pub fn with_provider_group(mut self, group_id: Guid) -> Self {
-        self.provider_group = ProviderGroup::Windows(group_id);
+    /// Set how the `time` field of each event is encoded.
+    /// The default is [`TimestampFormat::SystemTime`], which loses sub-second precision
+    /// on Linux.
+    /// Common Schema events always use RFC 3339 strings, as required by the schema.
+    pub fn with_timestamp_format(mut self, format: TimestampFormat) -> Self {
+        self.timestamp_format = format;
         self
     }
 
-    /// For advanced scenarios.
-    /// Set the EventHeader provider group to join this provider to.
-    #[cfg(any(target_os = "linux", doc))]
-    pub fn with_provider_group(mut self, name: &str) -> Self {
-        self.provider_group = ProviderGroup::Linux(Cow::Owned(name.to_owned()));
+    /// Choose which standard fields are added to each event.
+    /// The default is [`FieldSet::default`]: the timestamp, the formatted message
+    /// and the source location. Common Schema events are not affected.
+    pub fn with_field_set(mut self, field_set: FieldSet) -> Self {
+        self.field_set = field_set;
         self
     }
 
-    pub(crate) fn validate_config(&self) {
-        match &self.provider_group {
-            ProviderGroup::Unset => (),
-            ProviderGroup::Windows(guid) => {
-                assert_ne!(guid, &Guid::zero(), "Provider GUID must not be zeroes");
-            }
-            ProviderGroup::Linux(name) => {
-                assert!(
-                    eventheader_dynamic::ProviderOptions::is_valid_option_value(&name),
-                    "Provider names must be lower case ASCII or numeric digits"
-                );
-            }
-        }
+    /// Drop records less severe than `level`. The default is [`log::LevelFilter::Trace`],
+    /// which leaves filtering to the sessions listening for events.
+    pub fn with_max_level(mut self, level: log::LevelFilter) -> Self {
+        self.max_level = level;
+        self
+    }
 
-        #[cfg(all(target_os = "linux"))]
-        if self
-            .provider_name
-            .contains(|f: char| !f.is_ascii_alphanumeric())
-        {
-            // The perf command is very particular about the provider names it accepts.
-            // The Linux kernel itself cares less, and other event consumers should also presumably not need this check.
-            //panic!("Linux provider names must be ASCII alphanumeric");
-        }
+    /// Write a `LogEtwStats` event with the [`ProviderStats`] of each provider to the
+    /// default provider at most once every `interval`. The events are written while
+    /// logging other records, so none are written while the process is not logging.
+    pub fn with_stats_events(mut self, interval: Duration) -> Self {
+        self.stats_interval = Some(interval);
+        self
     }
 
-    pub fn install(self) {
-        self.validate_config();
+    /// Drop records that exceed `limit`, so that one noisy `log!` call cannot flood the trace
+    /// session or the other sinks. Records are checked as they are logged, before they are
+    /// encoded or queued for the background writer.
+    ///
+    /// Once records are suppressed, a `LogEtwSuppressed` event is written to the default
+    /// provider for each bucket, with the `Key` it limits (a provider, `provider:event` or
+    /// `file:line`), how many records it `Suppressed`, and the `FirstSuppressed` and
+    /// `LastSuppressed` times. Like [`ExporterBuilder::with_stats_events`], the events are
+    /// written by the first record logged after [`RateLimit::summary_interval`], so none are
+    /// written while the process is not logging. [`Log::flush`] writes them early.
+    /// The fallback and the other sinks get the same event, with the counts in its message.
+    ///
+    /// At most 4096 buckets are kept. Buckets that have refilled and have nothing to report
+    /// are forgotten when room is needed, and if there is still none, records that would
+    /// need a new bucket share one, reported with the `Key` `*`, until the next summary.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
 
-        let _ = log::set_boxed_logger(Box::new(EtwEventHeaderLogger::new(ExporterConfig {
+    /// Choose where records go if the native backend cannot be used, such as in a container
+    /// without access to user_events. The default is [`Fallback::Silent`].
+    /// Whether the backend is available is checked when the logger is installed or built,
+    /// and reported by [`LoggerHandle::status`].
+    pub fn with_fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Also write each record to stderr in a human-readable `format`, with the same name,
+    /// level, keyword and fields as the event written to ETW or user_events.
+    /// This is intended for local development. Typed events are not written to the console.
+    pub fn with_console(mut self, format: ConsoleFormat) -> Self {
+        self.console = Some(format);
+        self
+    }
+
+    /// Color the level of each [`ConsoleFormat::Compact`] line with ANSI escape codes.
+    /// JSON lines are never colored, so that they can be parsed.
+    pub fn with_console_colors(mut self) -> Self {
+        self.console_colors = true;
+        self
+    }
+
+    /// Also append each record to the file at `path` as a JSON object on its own line,
+    /// with the same name, descriptor and fields as the event written to ETW or user_events,
+    /// laid out like [`ConsoleFormat::Json`]. The file is created if needed,
+    /// and rotated according to `rotation`.
+    ///
+    /// This keeps a log on machines where no trace session is running.
+    /// Typed events are not written to the file.
+    pub fn with_json_file(mut self, path: impl Into<PathBuf>, rotation: FileRotation) -> Self {
+        self.json_file = Some((path.into(), rotation));
+        self
+    }
+
+    /// Write records on a background thread, so that logging only has to capture them.
+    /// Up to `capacity` records wait in a queue, and `overflow` decides what happens
+    /// to records logged while it is full. [`Log::flush`] waits for the queue to empty.
+    ///
+    /// Key-values that are not primitives or `_meta` overrides are captured as strings.
+    /// The background thread is fixed when the logger is installed or built,
+    /// and is not changed by [`EtwEventHeaderLogger::reconfigure`].
+    pub fn with_background_writer(mut self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        self.background = Some((capacity, overflow));
+        self
+    }
+
+    /// Do not write records to ETW or user_events, for example to log only to
+    /// the console while developing. No [`Fallback`] is used. Typed events are not affected.
+    pub fn without_native_events(mut self) -> Self {
+        self.native_events = false;
+        self
+    }
+
+    /// Set the clock used to timestamp events. The default is [`SystemClock`].
+    /// Use a [`FixedClock`](crate::clock::FixedClock) in tests that need byte-stable events.
+    ///
+    /// Individual records can also override their timestamp with a `_timestamp` key-value,
+    /// either as nanoseconds since the Unix epoch or as an RFC 3339 string.
+    /// This is intended for tools that re-emit historical records.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Declare the keywords that records use, replacing the default of keyword 1.
+    /// On Linux, a tracepoint for every log level and each of these keywords is registered
+    /// when a provider is created, so sessions can enable them before they are first used.
+    pub fn with_keywords(mut self, keywords: impl IntoIterator<Item = u64>) -> Self {
+        let keywords: Vec<u64> = keywords.into_iter().collect();
+        self.event_sets = log::Level::iter()
+            .flat_map(|lvl| {
+                keywords
+                    .iter()
+                    .map(move |keyword| (map_level(lvl), *keyword))
+            })
+            .collect();
+        self
+    }
+
+    /// Declare one more level and keyword combination, such as one used with a raw
+    /// level override in [`event::meta`](crate::event::meta).
+    pub fn with_event_set(mut self, level: u8, keyword: u64) -> Self {
+        if !self.event_sets.contains(&(level, keyword)) {
+            self.event_sets.push((level, keyword));
+        }
+        self
+    }
+
+    /// Set what happens to records whose level and keyword combination was not declared.
+    /// The default is [`RegistrationPolicy::Lazy`].
+    pub fn with_registration_policy(mut self, policy: RegistrationPolicy) -> Self {
+        self.registration_policy = policy;
+        self
+    }
+
+    /// Declare the providers that records are expected to use, by target or `_provider` name.
+    /// [`install`](Self::install) registers them, along with the default provider,
+    /// so sessions can enable them before the first event is written.
+    pub fn with_providers<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+        self.providers.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Send records whose target starts with `target_prefix` to `provider_name`, rather than
+    /// to a provider named after the target. The first matching route is used, and a
+    /// `_provider` key-value still takes precedence. The provider is declared as if by
+    /// [`with_providers`](Self::with_providers).
+    ///
+    /// ```no_run
+    /// log_etw::logger::new_logger("MyCompany_MyService")
+    ///     .with_route("hyper::", "MyCompany_MyService_Http")
+    ///     .install();
+    /// ```
+    pub fn with_route(
+        mut self,
+        target_prefix: impl Into<String>,
+        provider_name: impl Into<String>,
+    ) -> Self {
+        let provider_name = provider_name.into();
+        if !self.providers.contains(&provider_name) {
+            self.providers.push(provider_name.clone());
+        }
+        self.routes.push((target_prefix.into(), provider_name));
+        self
+    }
+
+    /// Give records whose target starts with `target_prefix` this keyword, rather than
+    /// keyword 1, so that sessions can enable them separately. The first matching entry
+    /// is used, and a `_keyword` key-value still takes precedence. The keyword is declared
+    /// for every log level, as if by [`with_event_set`](Self::with_event_set).
+    pub fn with_target_keyword(mut self, target_prefix: impl Into<String>, keyword: u64) -> Self {
+        self.keyword_map.push((target_prefix.into(), keyword));
+        self
+    }
+
+    /// For advanced scenarios.
+    /// Set the ETW provider group to join this provider to.
+    #[cfg(any(target_os = "windows", doc))]
+    pub fn with_provider_group(mut self, group_id: Guid) -> Self {
+        self.provider_group = ProviderGroup::Windows(group_id);
+        self
+    }
+
+    /// For advanced scenarios.
+    /// Set the EventHeader provider group to join this provider to.
+    #[cfg(any(target_os = "linux", doc))]
+    pub fn with_provider_group(mut self, name: &str) -> Self {
+        self.provider_group = ProviderGroup::Linux(Cow::Owned(name.to_owned()));
+        self
+    }
+
+    /// Override the builder's settings with any of these environment variables that are set:
+    ///
+    /// | Variable | Value |
+    /// |----------|-------|
+    /// | `LOG_ETW_PROVIDER` | The default provider name. Its ID is generated from the name, unless one was set with [`with_provider_id`](Self::with_provider_id). |
+    /// | `LOG_ETW_GROUP` | The provider group: a GUID on Windows, a group name on Linux. |
+    /// | `LOG_ETW_FILTER` | The most verbose level logged, such as `info`, or `off`. See [`with_max_level`](Self::with_max_level). |
+    /// | `LOG_ETW_JSON` | `true` or `false`: encode the payload as JSON. Requires the `json` feature. |
+    /// | `LOG_ETW_COMMON_SCHEMA` | `true` or `false`: emit Common Schema events. |
+    ///
+    /// Booleans also accept `1`, `0`, `yes`, `no`, `on` and `off`.
+    /// Returns an error naming the variable if a value is not valid.
+    pub fn with_env_overrides(self) -> Result<Self, Error> {
+        self.with_overrides_from(|name| std::env::var(name))
+    }
+
+    fn with_overrides_from(
+        mut self,
+        var: impl Fn(&str) -> Result<String, std::env::VarError>,
+    ) -> Result<Self, Error> {
+        let get = |name: &str| match var(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(err) => Err(invalid_env_var(name, err.to_string())),
+        };
+        let get_bool = |name: &str| {
+            get(name)?
+                .map(|value| {
+                    parse_bool(&value).ok_or_else(|| {
+                        invalid_env_var(name, format!("{:?} is not true or false", value))
+                    })
+                })
+                .transpose()
+        };
+
+        if let Some(name) = get("LOG_ETW_PROVIDER")? {
+            if self.provider_id == Guid::from_name(&self.provider_name) {
+                self.provider_id = Guid::from_name(&name);
+            }
+            self.provider_name = name;
+        }
+
+        if let Some(group) = get("LOG_ETW_GROUP")? {
+            self.provider_group = ProviderGroup::parse(&group)
+                .map_err(|reason| invalid_env_var("LOG_ETW_GROUP", reason))?;
+        }
+
+        if let Some(filter) = get("LOG_ETW_FILTER")? {
+            self.max_level = filter.parse().map_err(|_| {
+                invalid_env_var("LOG_ETW_FILTER", format!("{:?} is not a log level", filter))
+            })?;
+        }
+
+        if let Some(json) = get_bool("LOG_ETW_JSON")? {
+            if json && !cfg!(feature = "json") {
+                return Err(invalid_env_var(
+                    "LOG_ETW_JSON",
+                    "JSON payloads require the json feature".to_owned(),
+                ));
+            }
+            self.json = json;
+        }
+
+        if let Some(common_schema) = get_bool("LOG_ETW_COMMON_SCHEMA")? {
+            self.emit_common_schema_events = common_schema;
+        }
+
+        self.validate_config()?;
+        Ok(self)
+    }
+
+    pub(crate) fn validate_config(&self) -> Result<(), Error> {
+        let group_name_len = match &self.provider_group {
+            ProviderGroup::Unset => 0,
+            ProviderGroup::Windows(guid) => {
+                if guid == &Guid::zero() {
+                    return Err(Error::InvalidProviderGroup {
+                        reason: "the provider group ID must not be zeroes",
+                    });
+                }
+                0
+            }
+            ProviderGroup::Linux(name) => {
+                if !eventheader_dynamic::ProviderOptions::is_valid_option_value(name) {
+                    return Err(Error::InvalidProviderGroup {
+                        reason:
+                            "the provider group name must be lower case ASCII or numeric digits",
+                    });
+                }
+                name.len()
+            }
+        };
+
+        for name in std::iter::once(&self.provider_name).chain(&self.providers) {
+            if let Some(reason) = invalid_provider_name_reason(name, group_name_len) {
+                return Err(Error::InvalidProviderName {
+                    name: name.clone(),
+                    reason,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn into_config(self) -> Result<ExporterConfig, Error> {
+        self.into_config_replacing(None)
+    }
+
+    /// Like [`into_config`](Self::into_config), but reusing the sinks of `current`
+    /// that write to the same place, so that two sinks never append to one file.
+    pub(crate) fn into_config_replacing(
+        mut self,
+        current: Option<&ExporterConfig>,
+    ) -> Result<ExporterConfig, Error> {
+        self.validate_config()?;
+
+        // Done here rather than by `with_target_keyword`, since `with_keywords` replaces the sets
+        for (_, keyword) in &self.keyword_map {
+            for level in log::Level::iter() {
+                let set = (map_level(level), *keyword);
+                if !self.event_sets.contains(&set) {
+                    self.event_sets.push(set);
+                }
+            }
+        }
+
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        if let Some(format) = self.console {
+            sinks.push(Box::new(TextSink::stderr(format, self.console_colors)));
+        }
+        let json_file = match &self.json_file {
+            Some((path, rotation)) => {
+                let current = current
+                    .and_then(|current| current.json_file.as_ref())
+                    .filter(|sink| sink.is_same(path, rotation));
+                let sink = match current {
+                    Some(sink) => sink.clone(),
+                    None => Arc::new(JsonFileSink::new(path, rotation.clone()).map_err(|err| {
+                        Error::InvalidConfig {
+                            origin: path.display().to_string(),
+                            reason: err.to_string(),
+                        }
+                    })?),
+                };
+                sinks.push(Box::new(sink.clone()));
+                Some(sink)
+            }
+            None => None,
+        };
+
+        Ok(ExporterConfig {
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
             default_provider_name: self.provider_name,
             default_provider_id: self.provider_id,
             default_provider_group: self.provider_group,
             json: self.json,
             common_schema: self.emit_common_schema_events,
-        })));
-        log::set_max_level(log::LevelFilter::Trace);
+            timestamp_format: self.timestamp_format,
+            clock: self.clock,
+            field_set: self.field_set,
+            event_sets: self.event_sets,
+            registration_policy: self.registration_policy,
+            providers: self.providers,
+            routes: self.routes,
+            keyword_map: self.keyword_map,
+            max_level: self.max_level,
+            stats_interval: self.stats_interval,
+            rate_limit: self.rate_limit,
+            fallback: self.fallback,
+            native_events: self.native_events,
+            json_file,
+            sinks,
+        })
+    }
+
+    /// Build a logger without installing it as the global logger, so that it can be
+    /// called directly or from another logger's dispatch.
+    ///
+    /// The logger has its own providers, separate from those of the installed logger,
+    /// typed events and any other built logger. They are registered now, and unregistered
+    /// when the logger is shut down or dropped.
+    pub fn build(self) -> Result<EtwEventHeaderLogger, Error> {
+        let background = self.background;
+        let mut logger = EtwEventHeaderLogger::with_provider_cache(
+            self.into_config()?,
+            Arc::new(ProviderCache::new()),
+        );
+        logger.start(background)?;
+        Ok(logger)
+    }
+
+    /// Install the logger as the global logger.
+    /// Returns the providers that were registered up front, so that deployment scripts
+    /// can enable their provider IDs or tracepoints before the code paths that use them run.
+    ///
+    /// Panics if the configuration is invalid, and does nothing if a global logger is
+    /// already installed. Use [`try_install`](Self::try_install) to handle these errors.
+    pub fn install(self) -> Vec<RegisteredProvider> {
+        match self.try_install() {
+            Ok(handle) => handle.registered_providers().to_vec(),
+            Err(Error::AlreadyInstalled) => Vec::new(),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Install the logger as the global logger, or return why it could not be installed.
+    /// The returned handle can flush and shut down the logger.
+    pub fn try_install(self) -> Result<LoggerHandle, Error> {
+        #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+        return Err(Error::UnsupportedPlatform);
+
+        let background = self.background;
+        let config = self.into_config()?;
+
+        // Claim the global logger before registering providers or starting a thread,
+        // so that failing to install leaves nothing behind
+        let mut forwarding = INSTALLING.lock().unwrap();
+        if installed_logger().is_some() {
+            return Err(Error::AlreadyInstalled);
+        }
+        if !*forwarding {
+            log::set_logger(&ForwardToInstalled).map_err(|_| Error::AlreadyInstalled)?;
+            *forwarding = true;
+        }
+
+        let mut logger = Box::new(EtwEventHeaderLogger::new(config));
+        let registered = logger.start(background)?;
+
+        // The installed logger is kept for the life of the process
+        let logger: &'static EtwEventHeaderLogger = Box::leak(logger);
+        INSTALLED_LOGGER.store(logger as *const _ as *mut _, Ordering::Release);
+        log::set_max_level(logger.core.exporter_config.load().max_level);
+
+        Ok(LoggerHandle { logger, registered })
+    }
+}
+
+fn invalid_env_var(name: &str, reason: String) -> Error {
+    Error::InvalidConfig {
+        origin: format!("environment variable {}", name),
+        reason,
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Why a provider name cannot be registered, if it cannot.
+fn invalid_provider_name_reason(name: &str, group_name_len: usize) -> Option<&'static str> {
+    if name.is_empty() {
+        Some("the name must not be empty")
+    } else if name.contains('\0') {
+        Some("the name must not contain '\\0'")
+    } else if cfg!(target_os = "linux") && name.contains([' ', ':']) {
+        Some("user_events provider names must not contain ' ' or ':'")
+    } else if cfg!(target_os = "linux") && name.len() + group_name_len >= 234 {
+        Some("user_events provider and group names must total less than 234 bytes")
+    } else {
+        None
+    }
+}
+
+/// A handle to the installed global logger, returned by [`ExporterBuilder::try_install`].
+#[derive(Clone)]
+pub struct LoggerHandle {
+    logger: &'static EtwEventHeaderLogger,
+    registered: Vec<RegisteredProvider>,
+}
+
+impl LoggerHandle {
+    /// The providers that were registered when the logger was installed.
+    pub fn registered_providers(&self) -> &[RegisteredProvider] {
+        &self.registered
+    }
+
+    /// Flush any buffered events.
+    pub fn flush(&self) {
+        self.logger.flush();
+    }
+
+    /// The event counters of each provider. See [`EtwEventHeaderLogger::stats`].
+    pub fn stats(&self) -> Vec<ProviderStats> {
+        self.logger.stats()
+    }
+
+    /// The counters of the background writer. See [`EtwEventHeaderLogger::queue_stats`].
+    pub fn queue_stats(&self) -> Option<QueueStats> {
+        self.logger.queue_stats()
+    }
+
+    /// Whether the native backend could be used when the logger was installed.
+    /// See [`EtwEventHeaderLogger::status`].
+    pub fn status(&self) -> BackendStatus {
+        self.logger.status()
+    }
+
+    /// Replace the logger's configuration. See [`EtwEventHeaderLogger::reconfigure`].
+    pub fn reconfigure(&self, builder: ExporterBuilder) -> Result<(), Error> {
+        self.logger.reconfigure(builder)?;
+        log::set_max_level(self.logger.core.exporter_config.load().max_level);
+        Ok(())
+    }
+
+    /// Stop logging, flush the logger and unregister every provider, including those
+    /// used by typed events. Records and typed events written afterwards are dropped.
+    /// Calling this more than once does nothing.
+    ///
+    /// With a [background writer](ExporterBuilder::with_background_writer), this waits for
+    /// the writer thread to empty the queue and exit. On Windows, waiting for a thread while
+    /// the process is exiting or from `DllMain` deadlocks on the loader lock, so a logger
+    /// with a background writer must be shut down before then.
+    pub fn shutdown(&self) {
+        log::set_max_level(log::LevelFilter::Off);
+        self.logger.shutdown();
+    }
+}
+
+/// Shut down the logger installed by [`ExporterBuilder::try_install`] or
+/// [`ExporterBuilder::install`], if there is one. See [`LoggerHandle::shutdown`].
+///
+/// This is meant for exit hooks and dylib unload handlers that cannot reach the handle.
+/// On Windows, that only holds for a logger without a background writer: see
+/// [`LoggerHandle::shutdown`].
+pub fn shutdown() {
+    if let Some(logger) = installed_logger() {
+        log::set_max_level(log::LevelFilter::Off);
+        logger.shutdown();
+    }
+}
+
+/// The registration policy of the installed logger for a typed event at `level`, or `None` if
+/// the logger would drop a record at that level or has been shut down.
+/// [`RegistrationPolicy::Lazy`] if no logger is installed.
+pub(crate) fn typed_event_policy(level: log::Level) -> Option<RegistrationPolicy> {
+    if PROVIDER_CACHE.is_shut_down() {
+        return None;
+    }
+
+    let core = match installed_logger() {
+        Some(logger) => &logger.core,
+        None => return Some(RegistrationPolicy::Lazy),
+    };
+    if core.shut_down.load(Ordering::Acquire) {
+        return None;
+    }
+    let config = core.exporter_config.load();
+    (level <= config.max_level).then_some(config.registration_policy)
+}
+
+/// The event descriptor for a record, after applying any `_meta` overrides.
+pub(crate) struct EventOptions<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) level: u8,
+    pub(crate) keyword: u64,
+    pub(crate) opcode: u8,
+    pub(crate) id: u16,
+    pub(crate) version: u8,
+    pub(crate) tag: u32,
+}
+
+impl<'a> EventOptions<'a> {
+    pub(crate) fn new(level: log::Level) -> Self {
+        EventOptions {
+            name: "Event",
+            level: map_level(level),
+            keyword: 1,
+            opcode: 0,
+            id: 0,
+            version: 0,
+            tag: 0,
+        }
+    }
+}
+
+pub(crate) fn map_level(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => tracelogging::Level::Error.as_int(),
+        log::Level::Warn => tracelogging::Level::Warning.as_int(),
+        log::Level::Info => tracelogging::Level::Informational.as_int(),
+        log::Level::Debug => tracelogging::Level::Verbose.as_int(),
+        log::Level::Trace => tracelogging::Level::Verbose.as_int() + 1,
+    }
+}
+
+/// Interpret a `_timestamp` key-value as either nanoseconds since the Unix epoch
+/// or an RFC 3339 string.
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+pub(crate) fn timestamp_from_value(value: &log::kv::Value) -> Option<SystemTime> {
+    if let Some(nanos) = value.to_u64() {
+        Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_nanos(nanos))
+    } else if let Some(s) = value.to_borrowed_str() {
+        chrono::DateTime::parse_from_rfc3339(s)
+            .ok()
+            .map(SystemTime::from)
+    } else {
+        None
+    }
+}
+
+/// A logger that writes records to ETW on Windows and user_events on Linux.
+/// Create one with [`ExporterBuilder::install`] or [`ExporterBuilder::build`].
+pub struct EtwEventHeaderLogger {
+    // Shared with the background thread, if there is one
+    pub(crate) core: Arc<LoggerCore>,
+    background: Option<BackgroundWriter>,
+}
+
+/// The state of a logger, and how it writes records.
+pub(crate) struct LoggerCore {
+    // Swapped as a whole by `reconfigure`. Records load it once, without locking.
+    pub(crate) exporter_config: ArcSwap<ExporterConfig>,
+    provider_cache: Arc<ProviderCache>,
+    shut_down: AtomicBool,
+    created: Instant,
+    // Nanoseconds after `created` when the next stats events are due, or 0 before the first record.
+    next_stats: AtomicU64,
+    // Checked once by `start`, since it depends on the environment rather than the configuration
+    status: BackendStatus,
+    // Set while the backend is unavailable and the configured fallback is not silent
+    fallback_sink: ArcSwapOption<Box<dyn Sink>>,
+    pub(crate) rate_limiter: RateLimiter,
+}
+
+impl EtwEventHeaderLogger {
+    /// A logger that shares its providers with the installed logger and typed events.
+    pub(crate) fn new(exporter_config: ExporterConfig) -> EtwEventHeaderLogger {
+        Self::with_provider_cache(exporter_config, PROVIDER_CACHE.clone())
+    }
+
+    fn with_provider_cache(
+        exporter_config: ExporterConfig,
+        provider_cache: Arc<ProviderCache>,
+    ) -> EtwEventHeaderLogger {
+        EtwEventHeaderLogger {
+            core: Arc::new(LoggerCore {
+                exporter_config: ArcSwap::from_pointee(exporter_config),
+                provider_cache,
+                shut_down: AtomicBool::new(false),
+                created: Instant::now(),
+                next_stats: AtomicU64::new(0),
+                status: BackendStatus::Available,
+                fallback_sink: ArcSwapOption::empty(),
+                rate_limiter: RateLimiter::default(),
+            }),
+            background: None,
+        }
+    }
+
+    /// Register the providers, check whether the backend is available, open the fallback
+    /// sink if it is not, and start the background writer if there is one.
+    fn start(
+        &mut self,
+        background: Option<(usize, OverflowPolicy)>,
+    ) -> Result<Vec<RegisteredProvider>, Error> {
+        // Nothing else refers to the core yet
+        let core = Arc::get_mut(&mut self.core).expect("the logger was already started");
+        let registered = core.register_providers();
+
+        let config = core.exporter_config.load_full();
+        let error = core
+            .get_or_create_provider(&config, "")
+            .registration_error(&config.event_sets);
+        if error != 0 {
+            core.status = BackendStatus::Unavailable { error };
+        }
+
+        core.fallback_sink.store(core.open_fallback(&config)?);
+
+        self.background = background.map(|(capacity, overflow)| {
+            BackgroundWriter::spawn(self.core.clone(), capacity, overflow)
+        });
+        Ok(registered)
+    }
+
+    /// Whether the native backend could be used when the logger was created. If it could not,
+    /// records go to the [`Fallback`] chosen with [`ExporterBuilder::with_fallback`].
+    pub fn status(&self) -> BackendStatus {
+        self.core.status
+    }
+
+    /// Stop logging, flush the logger and unregister its providers.
+    /// Records logged afterwards are dropped. Calling this more than once does nothing.
+    ///
+    /// Shutting down a logger from [`ExporterBuilder::build`] does not affect any other logger.
+    pub fn shutdown(&self) {
+        if self.core.shut_down.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        self.flush();
+        if let Some(background) = &self.background {
+            background.stop();
+        }
+        self.core.provider_cache.shutdown();
+    }
+
+    /// Replace the configuration with that of `builder`, for example to turn on JSON payloads
+    /// or Common Schema events on a running service. Records that are already being written
+    /// finish with the old configuration.
+    ///
+    /// Providers and event sets declared by `builder` are registered now. The clock is replaced too.
+    /// So is the [`Fallback`], but whether the backend is available is not checked again.
+    /// A [JSON file](ExporterBuilder::with_json_file) with the same path and rotation is
+    /// kept open rather than opened again.
+    pub fn reconfigure(&self, builder: ExporterBuilder) -> Result<(), Error> {
+        let core = &self.core;
+        let config = builder.into_config_replacing(Some(&core.exporter_config.load()))?;
+        let fallback_sink = core.open_fallback(&config)?;
+        core.exporter_config.store(Arc::new(config));
+        core.fallback_sink.store(fallback_sink);
+        core.register_providers();
+        Ok(())
+    }
+
+    /// The event counters of each of the logger's providers, sorted by name.
+    /// Providers are forgotten when the logger is shut down.
+    pub fn stats(&self) -> Vec<ProviderStats> {
+        self.core.provider_cache.stats()
+    }
+
+    /// The counters of the background writer, if the logger has one.
+    /// See [`ExporterBuilder::with_background_writer`].
+    pub fn queue_stats(&self) -> Option<QueueStats> {
+        self.background.as_ref().map(BackgroundWriter::stats)
+    }
+}
+
+impl LoggerCore {
+    fn open_fallback(&self, config: &ExporterConfig) -> Result<Option<Arc<Box<dyn Sink>>>, Error> {
+        if self.status == BackendStatus::Available || !config.native_events {
+            return Ok(None);
+        }
+
+        let sink: Box<dyn Sink> = match &config.fallback {
+            Fallback::Silent => return Ok(None),
+            Fallback::Stderr => Box::new(TextSink::stderr(ConsoleFormat::Compact, false)),
+            Fallback::File(path) => {
+                Box::new(TextSink::file(path, ConsoleFormat::Compact).map_err(|err| {
+                    Error::InvalidConfig {
+                        origin: path.display().to_string(),
+                        reason: err.to_string(),
+                    }
+                })?)
+            }
+        };
+        Ok(Some(Arc::new(sink)))
+    }
+
+    fn write_stats_if_due(&self, config: &ExporterConfig, interval: Duration) {
+        let now = self.created.elapsed().as_nanos() as u64;
+        let due = self.next_stats.load(Ordering::Relaxed);
+        if due > now {
+            return;
+        }
+
+        // Only one thread writes each round of events
+        let next = now.saturating_add(interval.as_nanos() as u64).max(1);
+        if self
+            .next_stats
+            .compare_exchange(due, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+            || due == 0
+        {
+            return;
+        }
+
+        let provider = self.get_or_create_provider(config, "");
+        for stats in self.provider_cache.stats() {
+            provider.as_ref().write_typed(
+                "LogEtwStats",
+                map_level(log::Level::Info),
+                1,
+                0,
+                config.registration_policy,
+                |writer| {
+                    writer.add_str("Provider", &stats.name, FieldFormat::Default);
+                    writer.add_u64("Attempted", stats.attempted, FieldFormat::Default);
+                    writer.add_u64("Written", stats.written, FieldFormat::Default);
+                    writer.add_u64("Disabled", stats.disabled, FieldFormat::Default);
+                    writer.add_u64("Failed", stats.failed, FieldFormat::Default);
+                    writer.add_u64("Truncated", stats.truncated, FieldFormat::Default);
+                    writer.add_u32("LastError", stats.last_error, FieldFormat::Hex);
+                },
+            );
+        }
+    }
+
+    /// Write a `LogEtwSuppressed` event for each rate limit bucket that suppressed records,
+    /// as of `now`, or as of the clock if the summary is being written early.
+    /// The events go wherever records go: the native backend or the fallback, and the sinks.
+    fn write_suppressed(&self, config: &ExporterConfig, now: Option<SystemTime>) {
+        let now = now.unwrap_or_else(|| config.clock.now());
+        let suppressed = self
+            .rate_limiter
+            .take_suppressed(config.rate_limit.as_ref(), now);
+        if suppressed.is_empty() {
+            return;
+        }
+
+        let fallback_sink = self.fallback_sink.load();
+        let provider = (fallback_sink.is_none() && config.native_events)
+            .then(|| self.get_or_create_provider(config, ""));
+        let options = EventOptions {
+            name: "LogEtwSuppressed",
+            ..EventOptions::new(log::Level::Warn)
+        };
+        let write_sinks = |record: &log::Record| {
+            if let Some(sink) = &*fallback_sink {
+                sink.write_record(&config.default_provider_name, now, &options, record, config);
+            }
+            for sink in &config.sinks {
+                sink.write_record(&config.default_provider_name, now, &options, record, config);
+            }
+        };
+
+        let mut buffer = Vec::new();
+        for suppressed in suppressed {
+            let first =
+                String::from_utf8_lossy(format_rfc3339(&mut buffer, suppressed.first)).into_owned();
+            let last =
+                String::from_utf8_lossy(format_rfc3339(&mut buffer, suppressed.last)).into_owned();
+
+            if let Some(provider) = &provider {
+                provider.as_ref().write_typed(
+                    options.name,
+                    options.level,
+                    options.keyword,
+                    0,
+                    config.registration_policy,
+                    |writer| {
+                        writer.add_str("Key", &suppressed.key, FieldFormat::Default);
+                        writer.add_u64("Suppressed", suppressed.count, FieldFormat::Default);
+                        writer.add_str("FirstSuppressed", &first, FieldFormat::Default);
+                        writer.add_str("LastSuppressed", &last, FieldFormat::Default);
+                    },
+                );
+            }
+
+            if fallback_sink.is_some() || !config.sinks.is_empty() {
+                let mut builder = log::Record::builder();
+                builder.level(log::Level::Warn).target("");
+                #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+                let key_values: &[(&str, &dyn log::kv::ToValue)] = &[
+                    ("Key", &suppressed.key.as_str()),
+                    ("Suppressed", &suppressed.count),
+                    ("FirstSuppressed", &first.as_str()),
+                    ("LastSuppressed", &last.as_str()),
+                ];
+                #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+                builder.key_values(&key_values);
+                write_sinks(
+                    &builder
+                        .args(format_args!(
+                            "Suppressed records of {}: {} from {} to {}",
+                            suppressed.key, suppressed.count, first, last
+                        ))
+                        .build(),
+                );
+            }
+        }
+    }
+
+    fn get_or_create_provider(
+        &self,
+        config: &ExporterConfig,
+        target_provider_name: &str,
+    ) -> Pin<Arc<ProviderWrapper>> {
+        if target_provider_name.is_empty() {
+            // Since the target defaults to module_path!(), we never actually get here unless the developer uses target: ""
+            self.provider_cache.get_or_create(
+                &config.default_provider_name,
+                Some(&config.default_provider_id),
+                Some(&config.default_provider_group),
+                &config.event_sets,
+            )
+        } else {
+            self.provider_cache
+                .get_or_create(target_provider_name, None, None, &config.event_sets)
+            // TODO
+        }
+    }
+
+    /// Create the default provider and each declared provider, with all of the declared event sets.
+    fn register_providers(&self) -> Vec<RegisteredProvider> {
+        let config = &*self.exporter_config.load();
+
+        std::iter::once(("", &config.default_provider_name))
+            .chain(config.providers.iter().map(|name| (name.as_str(), name)))
+            .map(|(target, name)| {
+                let provider = self.get_or_create_provider(config, target);
+
+                #[cfg(target_os = "linux")]
+                let tracepoints = {
+                    // The provider may have been created earlier without these sets
+                    for (level, keyword) in &config.event_sets {
+                        provider.as_ref().register_set((*level).into(), *keyword);
+                    }
+
+                    let options = provider
+                        .as_ref()
+                        .get_provider()
+                        .read()
+                        .unwrap()
+                        .options()
+                        .to_owned();
+                    config
+                        .event_sets
+                        .iter()
+                        .map(|(level, keyword)| {
+                            format!("{}_L{:x}K{:x}{}", name, level, keyword, options)
+                        })
+                        .collect()
+                };
+                #[cfg(target_os = "windows")]
+                let tracepoints = {
+                    let _ = provider;
+                    Vec::new()
+                };
+
+                RegisteredProvider {
+                    name: name.clone(),
+                    id: if target.is_empty() {
+                        config.default_provider_id
+                    } else {
+                        Guid::from_name(name)
+                    },
+                    tracepoints,
+                }
+            })
+            .collect()
+    }
+
+    /// Like [`get_or_create_provider`](Self::get_or_create_provider), but cached per thread.
+    fn get_or_create_callsite(
+        &self,
+        config: &ExporterConfig,
+        target_provider_name: &str,
+    ) -> Rc<Callsite> {
+        if let Some(callsite) = cached_callsite(config.generation, target_provider_name) {
+            return callsite;
+        }
+
+        cache_callsite(Callsite::new(
+            config.generation,
+            target_provider_name,
+            self.get_or_create_provider(config, target_provider_name),
+        ))
+    }
+
+    /// Like [`get_or_create_callsite`](Self::get_or_create_callsite), but `None` rather than
+    /// creating and registering a provider that does not exist yet.
+    fn find_callsite(
+        &self,
+        config: &ExporterConfig,
+        target_provider_name: &str,
+    ) -> Option<Rc<Callsite>> {
+        if let Some(callsite) = cached_callsite(config.generation, target_provider_name) {
+            return Some(callsite);
+        }
+
+        let provider_name = if target_provider_name.is_empty() {
+            &config.default_provider_name
+        } else {
+            target_provider_name
+        };
+        let provider = self.provider_cache.get(provider_name)?;
+        Some(cache_callsite(Callsite::new(
+            config.generation,
+            target_provider_name,
+            provider,
+        )))
+    }
+}
+
+fn callsite_slot(generation: usize, name_ptr: usize) -> usize {
+    ((name_ptr >> 3) ^ generation) % CALLSITE_CACHE_SIZE
+}
+
+/// This thread's callsite for a provider name under the configuration `generation`, if any.
+fn cached_callsite(generation: usize, target_provider_name: &str) -> Option<Rc<Callsite>> {
+    let slot = callsite_slot(generation, target_provider_name.as_ptr() as usize);
+    CALLSITE_CACHE
+        .try_with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if let Some(callsite) = cache[slot]
+                .as_ref()
+                .filter(|callsite| callsite.matches(generation, target_provider_name))
+            {
+                return Some(callsite.clone());
+            }
+
+            // The same name at another address, such as a target that was copied
+            let callsite = cache
+                .iter()
+                .flatten()
+                .find(|callsite| callsite.matches(generation, target_provider_name))?
+                .clone();
+            cache[slot] = Some(callsite.clone());
+            Some(callsite)
+        })
+        .ok()
+        .flatten()
+}
+
+fn cache_callsite(callsite: Callsite) -> Rc<Callsite> {
+    let slot = callsite_slot(callsite.generation, callsite.name_ptr);
+    let callsite = Rc::new(callsite);
+
+    // The cache is unavailable while the thread is being torn down
+    let _ = CALLSITE_CACHE.try_with(|cache| {
+        cache.borrow_mut()[slot] = Some(callsite.clone());
+    });
+
+    callsite
+}
+
+/// Look up a provider in the shared provider cache, creating and registering it if needed.
+/// See [`ProviderCache::get_or_create`].
+pub(crate) fn get_or_create_cached_provider(
+    provider_name: &str,
+    provider_id: Option<&Guid>,
+    provider_group: Option<&ProviderGroup>,
+    event_sets: &[(u8, u64)],
+) -> Pin<Arc<ProviderWrapper>> {
+    PROVIDER_CACHE.get_or_create(provider_name, provider_id, provider_group, event_sets)
+}
+
+impl Drop for EtwEventHeaderLogger {
+    fn drop(&mut self) {
+        // Callsite caches on other threads can keep the providers alive, so unregister them now.
+        // The shared providers outlive any one logger.
+        if !Arc::ptr_eq(&self.core.provider_cache, &PROVIDER_CACHE) {
+            self.shutdown();
+        } else if let Some(background) = &self.background {
+            background.stop();
+        }
+    }
+}
+
+impl Log for EtwEventHeaderLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.core.enabled(metadata)
+    }
+
+    fn flush(&self) {
+        if let Some(background) = &self.background {
+            background.drain();
+        }
+        self.core
+            .write_suppressed(&self.core.exporter_config.load(), None);
+        self.core.flush();
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.core.shut_down.load(Ordering::Acquire) {
+            return;
+        }
+
+        let config = self.core.exporter_config.load();
+        if record.level() > config.max_level {
+            return;
+        }
+
+        // Capture the current timestamp ASAP
+        let timestamp = config.clock.now();
+        if let Some(limit) = &config.rate_limit {
+            let core = &self.core;
+            if core.rate_limiter.summary_due(timestamp) {
+                core.write_suppressed(&config, Some(timestamp));
+            }
+            if !core.rate_limiter.allow(limit, record, timestamp, &config) {
+                return;
+            }
+        }
+
+        if let Some(background) = &self.background {
+            let thread = RecordThread::capture(&config);
+            background.push(QueuedRecord::capture(record, timestamp, thread));
+        } else {
+            self.core.write(record, timestamp, &config);
+        }
+    }
+}
+
+impl LoggerCore {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        if self.shut_down.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let config = self.exporter_config.load();
+        if metadata.level() > config.max_level {
+            return false;
+        }
+
+        if self.fallback_sink.load().is_some() || !config.sinks.is_empty() {
+            return true;
+        } else if !config.native_events {
+            return false;
+        }
+
+        // Key-values that override the keyword are not known yet. A provider that has not been
+        // created yet is left to the first record, which finds out whether it is enabled.
+        match self.find_callsite(&config, config.route(metadata.target())) {
+            Some(callsite) => callsite.enabled(
+                map_level(metadata.level()),
+                config.keyword(metadata.target()),
+                &config,
+            ),
+            None => true,
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(sink) = &*self.fallback_sink.load() {
+            sink.flush();
+        }
+        for sink in &self.exporter_config.load().sinks {
+            sink.flush();
+        }
+    }
+
+    /// Write a record that passed the logger's filters, with the time it was logged.
+    pub(crate) fn write(
+        &self,
+        record: &log::Record,
+        timestamp: SystemTime,
+        config: &ExporterConfig,
+    ) {
+        config.resolve(record, timestamp, |provider_name, options, timestamp| {
+            if let Some(sink) = &*self.fallback_sink.load() {
+                sink.write_record(provider_name, timestamp, options, record, config);
+            } else if config.native_events {
+                let callsite = self.get_or_create_callsite(config, provider_name);
+                callsite.write_record(timestamp, options, record, config);
+            }
+
+            for sink in &config.sinks {
+                sink.write_record(provider_name, timestamp, options, record, config);
+            }
+        });
+
+        if let Some(interval) = config.stats_interval {
+            self.write_stats_if_due(config, interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    use crate::evt_meta;
+    #[allow(unused_imports)]
+    use log::{debug, error, info, warn};
+
+    use super::*;
+
+    // Only one global logger can be installed, so every test that logs through the
+    // `log` macros shares this one.
+    fn install_test_logger() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            new_logger("MyDefaultProviderName")
+                .try_install()
+                .expect("no other logger is installed by the tests");
+        });
+    }
+
+    #[test]
+    fn leap_second_rfc3339() {
+        let leap_second = chrono::NaiveDate::from_ymd_opt(2016, 12, 31)
+            .unwrap()
+            .and_hms_nano_opt(23, 59, 59, 1_500_000_000)
+            .unwrap()
+            .and_utc();
+        assert_eq!(
+            format_date_time(&mut Vec::new(), leap_second),
+            b"2016-12-31T23:59:59.999999999Z"
+        );
+    }
+
+    #[test]
+    fn provider_lookup() {
+        let logger = new_logger("LogEtwLookupDefault").build().unwrap();
+        let config = logger.core.exporter_config.load();
+
+        // A target names its own provider, and only an empty target uses the default provider
+        let target = logger
+            .core
+            .get_or_create_provider(&config, "LogEtwLookupTarget");
+        let default = logger.core.get_or_create_provider(&config, "");
+        assert!(!std::ptr::eq(&*target, &*default));
+
+        let names: Vec<_> = logger.stats().into_iter().map(|stats| stats.name).collect();
+        assert_eq!(names, ["LogEtwLookupDefault", "LogEtwLookupTarget"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn typed_event_sets() {
+        struct KeywordTwo;
+
+        impl crate::typed_events::LogEtwEvent for KeywordTwo {
+            const PROVIDER: &'static str = "LogEtwTypedSets";
+            const NAME: &'static str = "KeywordTwo";
+            const LEVEL: log::Level = log::Level::Info;
+            const KEYWORD: u64 = 0x2;
+            const OPCODE: u8 = 0;
+
+            fn add_fields(&self, _: &mut crate::typed_events::EventWriter) {}
+        }
+
+        // The record creates the provider with only the default event sets
+        install_test_logger();
+        info!(target: "LogEtwTypedSets", "Record");
+        let provider = get_or_create_cached_provider("LogEtwTypedSets", None, None, &[]);
+        let level = map_level(log::Level::Info).into();
+        assert!(provider.as_ref().find_set(level, 0x2).is_none());
+
+        crate::log_event!(KeywordTwo);
+        assert!(provider.as_ref().find_set(level, 0x2).is_some());
+    }
+
+    #[test]
+    fn try_install_errors() {
+        install_test_logger();
+        assert!(matches!(
+            new_logger("MyDefaultProviderName").try_install(),
+            Err(Error::AlreadyInstalled)
+        ));
+
+        // Failing to install registers nothing
+        assert!(matches!(
+            new_logger("LogEtwSecondInstall")
+                .with_providers(["LogEtwSecondInstallOther"])
+                .with_background_writer(16, OverflowPolicy::DropNewest)
+                .try_install(),
+            Err(Error::AlreadyInstalled)
+        ));
+        assert!(!PROVIDER_CACHE
+            .stats()
+            .iter()
+            .any(|stats| stats.name.starts_with("LogEtwSecondInstall")));
+
+        assert!(matches!(
+            new_logger("").into_config(),
+            Err(Error::InvalidProviderName { .. })
+        ));
+        assert!(matches!(
+            new_logger("MyDefaultProviderName")
+                .with_providers(["Bad\0Name"])
+                .into_config(),
+            Err(Error::InvalidProviderName { name, .. }) if name == "Bad\0Name"
+        ));
+
+        #[cfg(target_os = "linux")]
+        {
+            assert!(matches!(
+                new_logger("Bad:Name").into_config(),
+                Err(Error::InvalidProviderName { .. })
+            ));
+            assert!(matches!(
+                new_logger("MyDefaultProviderName")
+                    .with_provider_group("NotLowerCase")
+                    .into_config(),
+                Err(Error::InvalidProviderGroup { .. })
+            ));
+        }
+        #[cfg(target_os = "windows")]
+        assert!(matches!(
+            new_logger("MyDefaultProviderName")
+                .with_provider_group(Guid::zero())
+                .into_config(),
+            Err(Error::InvalidProviderGroup { .. })
+        ));
+    }
+
+    #[test]
+    fn env_overrides() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+                    .ok_or(std::env::VarError::NotPresent)
+            }
+        };
+
+        let builder = new_logger("MyDefaultProviderName")
+            .with_overrides_from(env(&[]))
+            .unwrap();
+        assert_eq!(builder.provider_name, "MyDefaultProviderName");
+        assert_eq!(builder.max_level, log::LevelFilter::Trace);
+
+        let builder = new_logger("MyDefaultProviderName")
+            .with_overrides_from(env(&[
+                ("LOG_ETW_PROVIDER", "LogEtwFromEnv"),
+                ("LOG_ETW_FILTER", "Warn"),
+                ("LOG_ETW_COMMON_SCHEMA", "yes"),
+            ]))
+            .unwrap();
+        assert_eq!(builder.provider_name, "LogEtwFromEnv");
+        assert_eq!(builder.provider_id, Guid::from_name("LogEtwFromEnv"));
+        assert_eq!(builder.max_level, log::LevelFilter::Warn);
+        assert!(builder.emit_common_schema_events);
+
+        // An explicit provider ID is kept
+        let id = Guid::from_name("SomethingElse");
+        let builder = new_logger("MyDefaultProviderName")
+            .with_provider_id(id)
+            .with_overrides_from(env(&[("LOG_ETW_PROVIDER", "LogEtwFromEnv")]))
+            .unwrap();
+        assert_eq!(builder.provider_id, id);
+
+        let error = |vars| match new_logger("MyDefaultProviderName").with_overrides_from(env(vars))
+        {
+            Err(err) => err,
+            Ok(_) => panic!("{:?} should be rejected", vars),
+        };
+        assert!(matches!(
+            error(&[("LOG_ETW_FILTER", "loud")]),
+            Error::InvalidConfig { origin, .. } if origin.contains("LOG_ETW_FILTER")
+        ));
+        assert!(matches!(
+            error(&[("LOG_ETW_COMMON_SCHEMA", "maybe")]),
+            Error::InvalidConfig { .. }
+        ));
+        assert!(matches!(
+            error(&[("LOG_ETW_PROVIDER", "")]),
+            Error::InvalidProviderName { .. }
+        ));
+        #[cfg(target_os = "linux")]
+        assert!(matches!(
+            error(&[("LOG_ETW_GROUP", "NotLowerCase")]),
+            Error::InvalidProviderGroup { .. }
+        ));
+        #[cfg(target_os = "windows")]
+        assert!(matches!(
+            error(&[("LOG_ETW_GROUP", "not-a-guid")]),
+            Error::InvalidConfig { .. }
+        ));
+    }
+
+    #[test]
+    fn timestamp_conversions() {
+        let ts = SystemTime::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+
+        assert_eq!(unix_nanos(ts), 1_000_000_000_123_456_789);
+        assert_eq!(
+            filetime(ts),
+            116_444_736_000_000_000 + 10_000_000_001_234_567
+        );
+        assert_eq!(
+            format_rfc3339(&mut Vec::new(), ts),
+            b"2001-09-09T01:46:40.123456789Z"
+        );
+        assert_eq!(
+            format_rfc3339(&mut Vec::new(), SystemTime::UNIX_EPOCH),
+            b"1970-01-01T00:00:00.000000000Z"
+        );
+
+        let mut buffer = Vec::new();
+        assert_eq!(
+            format_message(&format_args!("static"), &mut buffer),
+            b"static"
+        );
+        assert!(buffer.is_empty());
+        assert_eq!(
+            format_message(&format_args!("{} + {}", 1, 2), &mut buffer),
+            b"1 + 2"
+        );
+
+        let before_epoch = SystemTime::UNIX_EPOCH - std::time::Duration::from_secs(1);
+        assert_eq!(unix_nanos(before_epoch), 0);
+        assert_eq!(filetime(before_epoch), 116_444_736_000_000_000 - 10_000_000);
+    }
+
+    #[test]
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    fn timestamp_override() {
+        let expected = SystemTime::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 5);
+
+        assert_eq!(
+            timestamp_from_value(&log::kv::Value::from(1_000_000_000_000_000_005u64)),
+            Some(expected)
+        );
+        assert_eq!(
+            timestamp_from_value(&log::kv::Value::from("2001-09-09T01:46:40.000000005Z")),
+            Some(expected)
+        );
+        assert_eq!(
+            timestamp_from_value(&log::kv::Value::from("yesterday")),
+            None
+        );
+        assert_eq!(timestamp_from_value(&log::kv::Value::from(true)), None);
     }
-}
 
-pub(crate) fn map_level(level: log::Level) -> u8 {
-    match level {
-        log::Level::Error => tracelogging::Level::Error.as_int(),
-        log::Level::Warn => tracelogging::Level::Warning.as_int(),
-        log::Level::Info => tracelogging::Level::Informational.as_int(),
-        log::Level::Debug => tracelogging::Level::Verbose.as_int(),
-        log::Level::Trace => tracelogging::Level::Verbose.as_int() + 1,
+    #[test]
+    fn fixed_clock() {
+        use crate::clock::FixedClock;
+
+        let start = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let clock = FixedClock::new(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(std::time::Duration::from_nanos(100));
+        assert_eq!(clock.now(), start + std::time::Duration::from_nanos(100));
     }
-}
 
-struct EtwEventHeaderLogger {
-    exporter_config: ExporterConfig,
-}
+    #[test]
+    fn callsite_cache() {
+        let logger =
+            EtwEventHeaderLogger::new(new_logger("MyDefaultProviderName").into_config().unwrap());
 
-impl EtwEventHeaderLogger {
-    pub fn new(exporter_config: ExporterConfig) -> EtwEventHeaderLogger {
-        EtwEventHeaderLogger { exporter_config }
-    }
-
-    fn get_or_create_provider(&self, target_provider_name: &str) -> Pin<Arc<ProviderWrapper>> {
-        fn create_provider(
-            target_provider_name: &str,
-            exporter_config: &ExporterConfig,
-        ) -> Pin<Arc<ProviderWrapper>> {
-            let mut guard = PROVIDER_CACHE.write().unwrap();
-
-            let (provider_name, provider_id, provider_group) = if !target_provider_name.is_empty() {
-                (
-                    target_provider_name,
-                    Guid::from_name(target_provider_name),
-                    &ProviderGroup::Unset,
-                ) // TODO
-            } else {
-                // Since the target defaults to module_path!(), we never actually get here unless the developer uses target: ""
-                (
-                    exporter_config.default_provider_name.as_str(),
-                    exporter_config.default_provider_id,
-                    &exporter_config.default_provider_group,
-                )
-            };
+        let first = logger
+            .core
+            .get_or_create_callsite(&logger.core.exporter_config.load(), "MyRealProviderName");
+        let second = logger
+            .core
+            .get_or_create_callsite(&logger.core.exporter_config.load(), "MyRealProviderName");
+        assert!(Rc::ptr_eq(&first, &second));
 
-            // Check again to see if it has already been created before we got the write lock
-            if let Some(provider) = guard.get(provider_name) {
-                provider.clone()
-            } else {
-                guard.insert(
-                    provider_name.to_string(),
-                    ProviderWrapper::new(provider_name, &provider_id, provider_group),
+        // A name at a different address resolves to the same provider
+        let owned = String::from("MyRealProviderName");
+        let third = logger
+            .core
+            .get_or_create_callsite(&logger.core.exporter_config.load(), &owned);
+        assert!(Arc::ptr_eq(
+            &Pin::into_inner(first.provider.clone()),
+            &Pin::into_inner(third.provider.clone())
+        ));
+    }
+
+    #[test]
+    fn nested_logging() {
+        #[cfg(target_os = "windows")]
+        use crate::etw::build_record;
+        #[cfg(target_os = "linux")]
+        use crate::user_events::build_record;
+
+        static FORMATTED: AtomicUsize = AtomicUsize::new(0);
+
+        // Logs through the same logger while its own record is being formatted
+        struct Instrumented<'a>(&'a EtwEventHeaderLogger);
+
+        impl std::fmt::Display for Instrumented<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                let count = FORMATTED.fetch_add(1, Ordering::Relaxed) + 1;
+                self.0.log(
+                    &log::Record::builder()
+                        .level(log::Level::Info)
+                        .args(format_args!("Formatting {}", count))
+                        .build(),
                 );
+                f.write_str("instrumented")
+            }
+        }
 
-                if let Some(provider) = guard.get(provider_name) {
-                    provider.clone()
-                } else {
-                    panic!()
-                }
+        let path =
+            std::env::temp_dir().join(format!("log-etw-nested-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let logger = new_logger("LogEtwNested")
+            .with_field_set(FieldSet::minimal())
+            .with_json_file(&path, FileRotation::never())
+            .build()
+            .unwrap();
+
+        // Formatted by the sink, after the record's provider was looked up
+        logger.log(
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .args(format_args!("Outer {}", Instrumented(&logger)))
+                .build(),
+        );
+
+        // Formatted into the event buffers of the native event
+        let config = logger.core.exporter_config.load();
+        let options = EventOptions::new(log::Level::Info);
+        crate::buffers::with_event_buffers(|buffers| {
+            build_record(
+                buffers,
+                SystemTime::now(),
+                &options,
+                &log::Record::builder()
+                    .args(format_args!("While building {}", Instrumented(&logger)))
+                    .build(),
+                &config,
+            )
+        });
+        logger.flush();
+
+        assert_eq!(FORMATTED.load(Ordering::Relaxed), 2);
+        let payloads: Vec<_> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('"').nth(7).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            payloads,
+            ["Formatting 1", "Outer instrumented", "Formatting 2"]
+        );
+
+        drop(logger);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn declared_event_sets() {
+        let config = new_logger("MyDefaultProviderName")
+            .with_keywords([1, 0x10])
+            .with_event_set(200, 0x20)
+            .with_registration_policy(RegistrationPolicy::DeclaredOnly)
+            .into_config()
+            .unwrap();
+        assert_eq!(config.event_sets.len(), 11);
+
+        let provider =
+            get_or_create_cached_provider("LogEtwDeclaredSets", None, None, &config.event_sets);
+        for (level, keyword) in &config.event_sets {
+            assert!(provider
+                .as_ref()
+                .find_set((*level).into(), *keyword)
+                .is_some());
+        }
+
+        let callsite = Callsite::new(0, "LogEtwDeclaredSets", provider.clone());
+        assert!(callsite.event_set(200, 0x20, &config).is_some());
+        assert!(callsite.event_set(4, 0x40, &config).is_none());
+        assert!(provider.as_ref().find_set(4.into(), 0x40).is_none());
+
+        // Misses are cached along with the sets that were found
+        assert!(callsite.event_sets.borrow().contains(&(4, 0x40, None)));
+
+        let lazy_config = new_logger("MyDefaultProviderName").into_config().unwrap();
+        let callsite = Callsite::new(1, "LogEtwDeclaredSets", provider.clone());
+        assert!(callsite.event_set(4, 0x40, &lazy_config).is_some());
+        assert!(provider.as_ref().find_set(4.into(), 0x40).is_some());
+    }
+
+    #[test]
+    fn registered_providers() {
+        let logger = EtwEventHeaderLogger::new(
+            new_logger("LogEtwRegisteredDefault")
+                .with_providers(["LogEtwRegisteredOther"])
+                .with_event_set(4, 0x10)
+                .into_config()
+                .unwrap(),
+        );
+
+        let registered = logger.core.register_providers();
+        assert_eq!(registered.len(), 2);
+        assert_eq!(registered[0].name, "LogEtwRegisteredDefault");
+        assert_eq!(registered[1].name, "LogEtwRegisteredOther");
+        assert_eq!(registered[1].id, Guid::from_name("LogEtwRegisteredOther"));
+
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(registered[1].tracepoints.len(), 6);
+            assert!(registered[1]
+                .tracepoints
+                .contains(&"LogEtwRegisteredOther_L4K1".to_string()));
+            assert!(registered[1]
+                .tracepoints
+                .contains(&"LogEtwRegisteredOther_L4K10".to_string()));
+        }
+    }
+
+    #[test]
+    fn built_loggers() {
+        let first = new_logger("LogEtwBuiltFirst").build().unwrap();
+        let second = new_logger("LogEtwBuiltSecond")
+            .with_field_set(FieldSet::minimal())
+            .build()
+            .unwrap();
+
+        // Each logger has its own providers and callsites, even for the same target
+        let first_callsite = first
+            .core
+            .get_or_create_callsite(&first.core.exporter_config.load(), "MyRealProviderName");
+        let second_callsite = second
+            .core
+            .get_or_create_callsite(&second.core.exporter_config.load(), "MyRealProviderName");
+        assert!(!Rc::ptr_eq(&first_callsite, &second_callsite));
+        assert!(!Arc::ptr_eq(
+            &Pin::into_inner(first_callsite.provider.clone()),
+            &Pin::into_inner(second_callsite.provider.clone())
+        ));
+        assert!(Rc::ptr_eq(
+            &first_callsite,
+            &first
+                .core
+                .get_or_create_callsite(&first.core.exporter_config.load(), "MyRealProviderName")
+        ));
+
+        let record = log::Record::builder()
+            .target("MyRealProviderName")
+            .level(log::Level::Info)
+            .args(format_args!("Built logger"))
+            .build();
+        first.log(&record);
+        second.log(&record);
+
+        // Shutting down one logger leaves the others running
+        first.shutdown();
+        assert!(first.core.provider_cache.is_shut_down());
+        assert!(!second.core.provider_cache.is_shut_down());
+        assert!(!PROVIDER_CACHE.is_shut_down());
+        first.log(&record);
+        second.log(&record);
+    }
+
+    #[test]
+    fn fallback() {
+        let path = std::env::temp_dir().join(format!("log-etw-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let logger = new_logger("LogEtwFallback")
+            .with_fallback(Fallback::File(path.clone()))
+            .build()
+            .unwrap();
+        logger.log(
+            &log::Record::builder()
+                .target("LogEtwFallbackOther")
+                .level(log::Level::Warn)
+                .args(format_args!("Fallback {}", 1))
+                .build(),
+        );
+        logger.flush();
+
+        // The fallback is only used where user_events or ETW is not available, as in most containers
+        let contents = std::fs::read_to_string(&path).unwrap_or_default();
+        match logger.status() {
+            BackendStatus::Available => assert!(contents.is_empty()),
+            BackendStatus::Unavailable { error } => {
+                assert_ne!(error, 0);
+                assert!(contents.contains(" WARN  LogEtwFallbackOther:Event [0x1] Fallback 1"));
+                assert!(logger.enabled(&log::Metadata::builder().target("Any").build()));
             }
         }
 
-        fn get_provider(provider_name: &str) -> Option<Pin<Arc<ProviderWrapper>>> {
-            PROVIDER_CACHE.read().unwrap().get(provider_name).cloned()
+        // The fallback file is only opened if it is needed
+        let unwritable = new_logger("LogEtwFallback")
+            .with_fallback(Fallback::File(path.join("unwritable.log")))
+            .build();
+        match logger.status() {
+            BackendStatus::Available => assert!(unwritable.is_ok()),
+            BackendStatus::Unavailable { .. } => {
+                assert!(matches!(unwritable, Err(Error::InvalidConfig { .. })))
+            }
         }
 
-        let provider_name = if target_provider_name.is_empty() {
-            target_provider_name
-        } else {
-            self.exporter_config.default_provider_name.as_str()
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    #[test]
+    fn json_file() {
+        let path = std::env::temp_dir().join(format!("log-etw-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let logger = new_logger("LogEtwJsonFile")
+            .with_field_set(FieldSet::minimal())
+            .with_clock(crate::clock::FixedClock::new(SystemTime::UNIX_EPOCH))
+            .with_json_file(&path, FileRotation::never())
+            .build()
+            .unwrap();
+
+        let meta = crate::event::meta {
+            provider: "MyRealProviderName",
+            event_name: "RequestStarted",
+            keyword: 5,
+            opcode: crate::event::opcode::START,
+            ..Default::default()
         };
+        let kvs: &[(&str, &dyn log::kv::ToValue)] = &[("_meta", &meta), ("user_key", &1)];
+        logger.log(
+            &log::Record::builder()
+                .target("LogEtwJsonFileTarget")
+                .level(log::Level::Info)
+                .args(format_args!("Started"))
+                .key_values(&kvs)
+                .build(),
+        );
+        logger.flush();
 
-        if let Some(provider) = get_provider(provider_name) {
-            provider
-        } else {
-            create_provider(target_provider_name, &self.exporter_config)
+        // The same name, descriptor and fields as the native event
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"n\":\"MyRealProviderName:RequestStarted\",\"Payload\":\"Started\",\"user_key\":1,\
+             \"meta\":{\"time\":\"1970-01-01T00:00:00.000000000Z\",\"level\":4,\"keyword\":\"0x5\",\"opcode\":1}}\n"
+        );
+
+        drop(logger);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reconfigure_keeps_json_file() {
+        let path = std::env::temp_dir().join(format!("log-etw-{}-kept.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let builder =
+            || new_logger("LogEtwJsonFileKept").with_json_file(&path, FileRotation::never());
+        let logger = builder().build().unwrap();
+        let json_file = || {
+            logger
+                .core
+                .exporter_config
+                .load()
+                .json_file
+                .clone()
+                .unwrap()
+        };
+        let before = json_file();
+
+        // The same file keeps one sink, so that two never append to or rotate it at once
+        logger
+            .reconfigure(builder().with_max_level(log::LevelFilter::Warn))
+            .unwrap();
+        assert!(Arc::ptr_eq(&before, &json_file()));
+
+        let rotation = FileRotation {
+            max_bytes: Some(1 << 20),
+            ..FileRotation::never()
+        };
+        logger
+            .reconfigure(new_logger("LogEtwJsonFileKept").with_json_file(&path, rotation))
+            .unwrap();
+        assert!(!Arc::ptr_eq(&before, &json_file()));
+
+        drop(logger);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rate_limit() {
+        let path = std::env::temp_dir().join(format!("log-etw-limit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let clock = Arc::new(crate::clock::FixedClock::new(SystemTime::UNIX_EPOCH));
+        let limit = RateLimit {
+            summary_interval: Duration::from_secs(10),
+            ..RateLimit::per_second(1, 2)
+        };
+        let logger = new_logger("LogEtwRateLimit")
+            .with_field_set(FieldSet::minimal())
+            .with_clock(clock.clone())
+            .with_json_file(&path, FileRotation::never())
+            .with_rate_limit(limit.clone())
+            .build()
+            .unwrap();
+
+        let log = |target: &str, n: usize| {
+            logger.log(
+                &log::Record::builder()
+                    .target(target)
+                    .level(log::Level::Warn)
+                    .args(format_args!("{}", n))
+                    .build(),
+            )
+        };
+        let summaries = || logger.stats()[0].attempted;
+
+        // Each event name has its own bucket of 2 tokens, earning one a second
+        for n in 0..5 {
+            log("LogEtwRateLimitA", n);
         }
+        log("LogEtwRateLimitB", 5);
+        clock.advance(Duration::from_secs(1));
+        log("LogEtwRateLimitA", 6);
+        log("LogEtwRateLimitA", 7);
+        logger.core.flush();
+
+        let payloads: Vec<_> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split('"').nth(7).unwrap().to_owned())
+            .collect();
+        assert_eq!(payloads, ["0", "1", "5", "6"]);
+
+        // The summary is written by the first record logged once it is due
+        assert_eq!(logger.stats()[0].name, "LogEtwRateLimit");
+        assert_eq!(summaries(), 0);
+        clock.advance(Duration::from_secs(9));
+        log("LogEtwRateLimitB", 8);
+        assert_eq!(summaries(), 1);
+
+        // What was suppressed since the last summary
+        log("LogEtwRateLimitA", 9);
+        log("LogEtwRateLimitA", 10);
+        log("LogEtwRateLimitA", 11);
+        clock.advance(Duration::from_secs(1));
+        log("LogEtwRateLimitA", 12);
+        log("LogEtwRateLimitA", 13);
+        assert_eq!(
+            logger
+                .core
+                .rate_limiter
+                .take_suppressed(Some(&limit), clock.now()),
+            [crate::rate_limit::Suppressed {
+                key: "LogEtwRateLimitA:Event".to_owned(),
+                count: 2,
+                first: SystemTime::UNIX_EPOCH + Duration::from_secs(10),
+                last: SystemTime::UNIX_EPOCH + Duration::from_secs(11),
+            }]
+        );
+
+        // Flushing writes the summary early
+        log("LogEtwRateLimitA", 14);
+        logger.flush();
+        assert_eq!(summaries(), 2);
+
+        // The summaries go to the sinks too
+        let summaries: Vec<_> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains("LogEtwSuppressed"))
+            .map(|line| line.split('"').nth(7).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            summaries,
+            [
+                "Suppressed records of LogEtwRateLimitA:Event: 4 \
+                 from 1970-01-01T00:00:00.000000000Z to 1970-01-01T00:00:01.000000000Z",
+                "Suppressed records of LogEtwRateLimitA:Event: 1 \
+                 from 1970-01-01T00:00:11.000000000Z to 1970-01-01T00:00:11.000000000Z",
+            ]
+        );
+
+        drop(logger);
+        let _ = std::fs::remove_file(&path);
     }
-}
 
-impl Log for EtwEventHeaderLogger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        let provider = self.get_or_create_provider(metadata.target());
-        provider.enabled(map_level(metadata.level()), 0)
+    #[test]
+    fn background_writer() {
+        // Holds up the background thread until it is opened
+        #[derive(Default)]
+        struct Gate {
+            open: std::sync::Mutex<bool>,
+            opened: std::sync::Condvar,
+            entered: AtomicBool,
+            written: std::sync::Mutex<Vec<(String, u32)>>,
+        }
+
+        impl Gate {
+            fn open(&self) {
+                *self.open.lock().unwrap() = true;
+                self.opened.notify_all();
+            }
+        }
+
+        impl crate::sinks::Sink for Arc<Gate> {
+            fn write_record(
+                &self,
+                _: &str,
+                _: SystemTime,
+                _: &EventOptions,
+                record: &log::Record,
+                _: &ExporterConfig,
+            ) {
+                self.entered.store(true, Ordering::Release);
+                let mut open = self.open.lock().unwrap();
+                while !*open {
+                    open = self.opened.wait(open).unwrap();
+                }
+                self.written
+                    .lock()
+                    .unwrap()
+                    .push((record.args().to_string(), current_thread_id()));
+            }
+        }
+
+        let run = |capacity, overflow| {
+            let gate = Arc::new(Gate::default());
+            let mut config = new_logger("LogEtwBackground")
+                .without_native_events()
+                .into_config()
+                .unwrap();
+            config.sinks.push(Box::new(gate.clone()));
+            let mut logger =
+                EtwEventHeaderLogger::with_provider_cache(config, Arc::new(ProviderCache::new()));
+            logger.start(Some((capacity, overflow))).unwrap();
+
+            let log = |n: usize| {
+                logger.log(
+                    &log::Record::builder()
+                        .level(log::Level::Info)
+                        .args(format_args!("{}", n))
+                        .build(),
+                )
+            };
+
+            // The first record is being written, and the rest wait in the queue or overflow it
+            log(0);
+            while !gate.entered.load(Ordering::Acquire) {
+                std::thread::yield_now();
+            }
+            if overflow == OverflowPolicy::Block {
+                let gate = gate.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(20));
+                    gate.open();
+                });
+            }
+            for n in 1..5 {
+                log(n);
+            }
+
+            gate.open();
+            logger.flush();
+            let written = std::mem::take(&mut *gate.written.lock().unwrap());
+            (written, logger.queue_stats().unwrap())
+        };
+
+        let (written, stats) = run(2, OverflowPolicy::DropNewest);
+        let messages: Vec<_> = written
+            .iter()
+            .map(|(message, _)| message.as_str())
+            .collect();
+        assert_eq!(messages, ["0", "1", "2"]);
+        assert_eq!((stats.queued, stats.written, stats.dropped), (3, 3, 2));
+
+        // Records report the thread that logged them, not the background thread
+        assert!(written
+            .iter()
+            .all(|(_, thread)| *thread == current_thread_id()));
+
+        let (written, stats) = run(2, OverflowPolicy::DropOldest);
+        let messages: Vec<_> = written
+            .iter()
+            .map(|(message, _)| message.as_str())
+            .collect();
+        assert_eq!(messages, ["0", "3", "4"]);
+        assert_eq!((stats.queued, stats.written, stats.dropped), (5, 3, 2));
+
+        let (written, stats) = run(1, OverflowPolicy::Block);
+        assert_eq!(written.len(), 5);
+        assert_eq!((stats.written, stats.dropped), (5, 0));
+        assert!(stats.blocked > 0);
     }
 
-    fn flush(&self) {}
+    #[test]
+    fn record_thread_scope_panics() {
+        let config = new_logger("LogEtwRecordThread").into_config().unwrap();
+        let captured = std::thread::Builder::new()
+            .name("captured".to_owned())
+            .spawn(move || (RecordThread::capture(&config), os_thread_id()))
+            .unwrap()
+            .join()
+            .unwrap();
 
-    fn log(&self, record: &log::Record) {
-        // Capture the current timestamp ASAP
-        let timestamp = SystemTime::now();
-
-        let mut provider_name = record.target();
-        let mut event_name = "Event";
-        let mut keywords = 1;
-
-        if cfg!(feature = "kv_unstable_json") {
-            if let Some(meta) = record.key_values().get("_meta".into()) {
-                if let Some(meta) = meta.downcast_ref::<crate::event::meta>() {
-                    provider_name = meta.provider;
-                    event_name = meta.event_name;
-                    keywords = meta.keyword;
+        let (thread, id) = captured;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            thread.scope(|| {
+                assert_eq!(current_thread_id(), id);
+                with_thread_name(|name| assert_eq!(name, Some("captured")));
+                panic!("the record panicked");
+            })
+        }));
+        assert!(result.is_err());
+
+        // Records written afterwards report the thread that writes them
+        assert_eq!(current_thread_id(), os_thread_id());
+        with_thread_name(|name| assert_eq!(name, std::thread::current().name()));
+    }
+
+    #[test]
+    fn background_writer_panics() {
+        struct PanickingSink;
+
+        impl crate::sinks::Sink for PanickingSink {
+            fn write_record(
+                &self,
+                _: &str,
+                _: SystemTime,
+                _: &EventOptions,
+                record: &log::Record,
+                _: &ExporterConfig,
+            ) {
+                if record.args().to_string() == "panic" {
+                    panic!("the sink panicked");
                 }
             }
         }
 
-        let provider = self.get_or_create_provider(provider_name);
-        provider.as_ref().write_record(
-            timestamp,
-            event_name,
-            keywords,
-            record,
-            &self.exporter_config,
+        let mut config = new_logger("LogEtwBackgroundPanics")
+            .without_native_events()
+            .into_config()
+            .unwrap();
+        config.sinks.push(Box::new(PanickingSink));
+        let mut logger =
+            EtwEventHeaderLogger::with_provider_cache(config, Arc::new(ProviderCache::new()));
+        logger.start(Some((1, OverflowPolicy::Block))).unwrap();
+
+        let log = |message: &str| {
+            logger.log(
+                &log::Record::builder()
+                    .level(log::Level::Info)
+                    .args(format_args!("{}", message))
+                    .build(),
+            )
+        };
+
+        // The record that panicked is dropped, and the thread goes on to write the next one
+        for message in ["before", "panic", "after"] {
+            log(message);
+        }
+        logger.flush();
+        let stats = logger.queue_stats().unwrap();
+        assert_eq!((stats.queued, stats.written, stats.panicked), (3, 2, 1));
+        assert!(stats.running);
+
+        // Once the thread has exited, a full queue drops records rather than blocking
+        logger.background.as_ref().unwrap().stop();
+        for _ in 0..3 {
+            log("stopped");
+        }
+        logger.flush();
+        let stats = logger.queue_stats().unwrap();
+        assert_eq!((stats.queued, stats.dropped), (4, 2));
+        assert!(!stats.running);
+    }
+
+    #[test]
+    fn callsite_lookup_by_contents() {
+        let logger = new_logger("LogEtwCallsiteDefault").build().unwrap();
+        let config = logger.core.exporter_config.load();
+        let target = String::from("LogEtwCallsiteTarget");
+        let callsite = logger.core.get_or_create_callsite(&config, &target);
+
+        // A copy of the name finds the same callsite, and then finds it at its own address
+        let copy = target.clone();
+        for _ in 0..2 {
+            let found = cached_callsite(config.generation, &copy).unwrap();
+            assert!(Rc::ptr_eq(&callsite, &found));
+        }
+        assert!(cached_callsite(config.generation + 1, &copy).is_none());
+    }
+
+    #[test]
+    fn enabled_does_not_create_providers() {
+        let config = new_logger("LogEtwEnabledDefault").into_config().unwrap();
+        let logger =
+            EtwEventHeaderLogger::with_provider_cache(config, Arc::new(ProviderCache::new()));
+        let info = log::Metadata::builder()
+            .target("LogEtwEnabledTarget")
+            .level(log::Level::Info)
+            .build();
+
+        // Until a record creates the provider, no session can be listening for it
+        assert!(logger.enabled(&info));
+        assert!(logger.stats().is_empty());
+
+        logger.log(
+            &log::Record::builder()
+                .metadata(info.clone())
+                .args(format_args!("Created"))
+                .build(),
         );
+        assert_eq!(logger.stats()[0].name, "LogEtwEnabledTarget");
+        assert!(!logger.enabled(&info));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::evt_meta;
-    #[allow(unused_imports)]
-    use log::{debug, error, info, warn};
+    #[test]
+    fn reconfigure() {
+        let logger = new_logger("LogEtwReconfigure").build().unwrap();
+        let info = log::Metadata::builder()
+            .target("MyRealProviderName")
+            .level(log::Level::Info)
+            .build();
+        let config = logger.core.exporter_config.load_full();
+        let before = logger
+            .core
+            .get_or_create_callsite(&config, "MyRealProviderName");
+        logger.enabled(&info);
 
-    use super::*;
+        logger
+            .reconfigure(
+                new_logger("LogEtwReconfigured")
+                    .with_common_schema_events()
+                    .with_max_level(log::LevelFilter::Warn),
+            )
+            .unwrap();
+        assert!(!logger.enabled(&info));
+
+        let config = logger.core.exporter_config.load_full();
+        assert!(config.common_schema);
+        assert_eq!(config.default_provider_name, "LogEtwReconfigured");
+
+        // Callsites resolved under the old configuration are not reused
+        let after = logger
+            .core
+            .get_or_create_callsite(&config, "MyRealProviderName");
+        assert!(!Rc::ptr_eq(&before, &after));
+
+        // An invalid configuration leaves the current one in place
+        assert!(logger.reconfigure(new_logger("")).is_err());
+        assert_eq!(
+            logger.core.exporter_config.load().default_provider_name,
+            "LogEtwReconfigured"
+        );
+    }
+
+    #[test]
+    fn reconfigure_while_logging() {
+        let logger = Arc::new(
+            new_logger("LogEtwRace")
+                .with_route("LogEtwRace", "LogEtwRace0")
+                .build()
+                .unwrap(),
+        );
+        let attempted = |name: &str| {
+            logger
+                .stats()
+                .into_iter()
+                .find(|stats| stats.name == name)
+                .map_or(0, |stats| stats.attempted)
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (stopped, wait_for_stop) = std::sync::mpsc::channel();
+        let (go, wait_for_go) = std::sync::mpsc::channel();
+        let writer = {
+            let logger = logger.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let log = || {
+                    logger.log(
+                        &log::Record::builder()
+                            .target("LogEtwRace::worker")
+                            .level(log::Level::Info)
+                            .args(format_args!("Racing"))
+                            .build(),
+                    )
+                };
+                while !stop.load(Ordering::Acquire) {
+                    log();
+                }
+                stopped.send(()).unwrap();
+                wait_for_go.recv().unwrap();
+                for _ in 0..10 {
+                    log();
+                }
+            })
+        };
+
+        // Each configuration routes the worker's records to the other provider
+        for n in 1..=50 {
+            logger
+                .reconfigure(
+                    new_logger("LogEtwRace")
+                        .with_route("LogEtwRace", format!("LogEtwRace{}", n % 2)),
+                )
+                .unwrap();
+        }
+        stop.store(true, Ordering::Release);
+        wait_for_stop.recv().unwrap();
+
+        // No callsite resolved under an old configuration is used with the last one
+        let before = [attempted("LogEtwRace0"), attempted("LogEtwRace1")];
+        go.send(()).unwrap();
+        writer.join().unwrap();
+        assert_eq!(
+            [attempted("LogEtwRace0"), attempted("LogEtwRace1")],
+            [before[0] + 10, before[1]]
+        );
+    }
+
+    #[test]
+    fn routes() {
+        let logger = new_logger("LogEtwRoutes")
+            .with_route("LogEtwRoutes::http", "LogEtwRoutesHttp")
+            .with_route("LogEtwRoutes", "LogEtwRoutesOther")
+            .build()
+            .unwrap();
+
+        // The first matching route wins
+        let config = logger.core.exporter_config.load();
+        assert_eq!(
+            config.route("LogEtwRoutes::http::client"),
+            "LogEtwRoutesHttp"
+        );
+        assert_eq!(config.route("LogEtwRoutes::db"), "LogEtwRoutesOther");
+        assert_eq!(config.route("LogEtwUnrouted"), "LogEtwUnrouted");
+
+        logger.log(
+            &log::Record::builder()
+                .target("LogEtwRoutes::http::client")
+                .level(log::Level::Info)
+                .args(format_args!("Routed"))
+                .build(),
+        );
+
+        // Routed providers are registered up front, like declared ones
+        let stats: Vec<_> = logger
+            .stats()
+            .into_iter()
+            .map(|stats| (stats.name, stats.attempted))
+            .collect();
+        assert_eq!(
+            stats,
+            [
+                ("LogEtwRoutes".to_owned(), 0),
+                ("LogEtwRoutesHttp".to_owned(), 1),
+                ("LogEtwRoutesOther".to_owned(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn keyword_map() {
+        let path =
+            std::env::temp_dir().join(format!("log-etw-keywords-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let logger = new_logger("LogEtwKeywords")
+            .with_target_keyword("LogEtwKeywordsDb", 0x4)
+            .with_keywords([1])
+            .with_field_set(FieldSet::minimal())
+            .with_json_file(&path, FileRotation::never())
+            .build()
+            .unwrap();
+
+        // Mapped keywords are declared even if the declared keywords are replaced afterwards
+        let config = logger.core.exporter_config.load();
+        assert!(config
+            .event_sets
+            .contains(&(map_level(log::Level::Trace), 0x4)));
+        assert_eq!(config.keyword("LogEtwKeywordsDbPool"), 0x4);
+        assert_eq!(config.keyword("LogEtwKeywordsHttp"), 1);
+
+        for target in ["LogEtwKeywordsDbPool", "LogEtwKeywordsHttp"] {
+            logger.log(
+                &log::Record::builder()
+                    .target(target)
+                    .level(log::Level::Info)
+                    .args(format_args!("Keyword"))
+                    .build(),
+            );
+        }
+        logger.flush();
+
+        let keywords: Vec<_> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split("\"keyword\":").nth(1).unwrap()[..5].to_owned())
+            .collect();
+        assert_eq!(keywords, ["\"0x4\"", "\"0x1\""]);
+
+        drop(logger);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn provider_stats() {
+        let counters = ProviderCounters::default();
+        counters.write_result(0);
+        counters.write_result(EVENT_TOO_LARGE);
+        counters.write_result(9);
+        counters.disabled();
+        assert_eq!(
+            counters.snapshot("Counters"),
+            ProviderStats {
+                name: "Counters".to_owned(),
+                attempted: 4,
+                written: 1,
+                disabled: 1,
+                failed: 1,
+                truncated: 1,
+                last_error: 9,
+            }
+        );
+
+        let logger = new_logger("LogEtwStatsDefault")
+            .with_stats_events(Duration::ZERO)
+            .build()
+            .unwrap();
+        let record = log::Record::builder()
+            .target("LogEtwStatsTarget")
+            .level(log::Level::Info)
+            .args(format_args!("Counted"))
+            .build();
+        logger.log(&record);
+        logger.log(&record);
+
+        let stats = logger.stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "LogEtwStatsDefault");
+        assert_eq!(stats[1].name, "LogEtwStatsTarget");
+        assert_eq!(stats[1].attempted, 2);
+
+        // The first record only schedules the stats events, and no session is listening,
+        // so the second record's stats events are counted as disabled.
+        assert_eq!(stats[0].attempted, 2);
+        assert_eq!(stats[0].disabled, stats[0].attempted - stats[0].written);
+    }
+
+    #[test]
+    fn field_sets() {
+        let logger = EtwEventHeaderLogger::new(
+            new_logger("MyDefaultProviderName")
+                .with_field_set(FieldSet {
+                    thread_id: true,
+                    thread_name: true,
+                    process_id: true,
+                    target: true,
+                    level: true,
+                    ..FieldSet::minimal()
+                })
+                .into_config()
+                .unwrap(),
+        );
+
+        logger.log(
+            &log::Record::builder()
+                .target("MyRealProviderName")
+                .level(log::Level::Info)
+                .args(format_args!("All the extras"))
+                .build(),
+        );
+
+        assert_ne!(current_thread_id(), 0);
+        assert!(!FieldSet::minimal().file);
+        assert!(FieldSet::default().file);
+    }
 
     #[test]
     fn test1() {
-        new_logger("MyDefaultProviderName").install();
+        install_test_logger();
 
         warn!(target: "MyRealProviderName", "My warning message");
 
@@ -385,16 +3522,195 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "kv_unstable_json")]
     fn test2() {
-        new_logger("MyDefaultProviderName").install();
+        install_test_logger();
 
-        info!(target: "FakeProviderName", _meta = log::kv::Value::capture_serde(&crate::event::meta{ provider: "MyRealProviderName", event_name: "test2", keyword: 5u64}); "My info message");
+        info!(target: "FakeProviderName", _meta = log::kv::Value::capture_serde(&crate::event::meta{ provider: "MyRealProviderName", event_name: "test2", keyword: 5u64, ..Default::default()}); "My info message");
     }
 
     #[test]
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
     fn test3() {
-        new_logger("MyDefaultProviderName").install();
+        install_test_logger();
 
         debug!(_meta = evt_meta!("MyRealProviderName", "test2", 5u64); "My debug message");
+
+        // Every field given, with nothing left to default
+        debug!(
+            _meta = evt_meta!(
+                "MyRealProviderName",
+                "test3",
+                5u64,
+                opcode: crate::event::opcode::START,
+                level: 5,
+                id: 100,
+                version: 1,
+                tag: 0x10,
+            );
+            "My debug message"
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    fn event_descriptor_overrides() {
+        use crate::event::{meta, opcode};
+
+        let path = std::env::temp_dir().join(format!("log-etw-desc-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let logger = new_logger("LogEtwDescriptors")
+            .with_field_set(FieldSet::minimal())
+            .with_clock(crate::clock::FixedClock::new(SystemTime::UNIX_EPOCH))
+            .with_json_file(&path, FileRotation::never())
+            .build()
+            .unwrap();
+
+        let log = |level: log::Level, meta: meta| {
+            let kvs: &[(&str, &dyn log::kv::ToValue)] = &[("_meta", &meta)];
+            logger.log(
+                &log::Record::builder()
+                    .level(level)
+                    .args(format_args!("{}", meta.event_name))
+                    .key_values(&kvs)
+                    .build(),
+            );
+        };
+        let provider = "MyRealProviderName";
+
+        log(
+            log::Level::Info,
+            meta {
+                provider,
+                event_name: "RequestStarted",
+                keyword: 5,
+                opcode: opcode::START,
+                id: 100,
+                version: 2,
+                tag: 0x10,
+                ..Default::default()
+            },
+        );
+        log(
+            log::Level::Info,
+            meta {
+                provider,
+                event_name: "RequestStopped",
+                keyword: 5,
+                opcode: opcode::STOP,
+                id: 101,
+                version: 2,
+                ..Default::default()
+            },
+        );
+        log(
+            log::Level::Warn,
+            meta {
+                provider,
+                event_name: "Critical",
+                keyword: 5,
+                level: 1,
+                opcode: 240,
+                ..Default::default()
+            },
+        );
+        logger.flush();
+
+        let metas: Vec<_> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once(",\"level\":").unwrap().1.to_owned())
+            .collect();
+        assert_eq!(
+            metas,
+            [
+                "4,\"keyword\":\"0x5\",\"opcode\":1,\"id\":100,\"version\":2,\"tag\":\"0x10\"}}",
+                "4,\"keyword\":\"0x5\",\"opcode\":2,\"id\":101,\"version\":2}}",
+                "1,\"keyword\":\"0x5\",\"opcode\":240}}",
+            ]
+        );
+
+        drop(logger);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    fn dynamic_names() {
+        use crate::event::{intern, owned_meta, ReservedKeys};
+
+        let tenant = format!("Tenant{}", 42);
+        assert!(std::ptr::eq(intern(&tenant), intern("Tenant42")));
+
+        let meta = owned_meta {
+            id: 3,
+            ..owned_meta::interned(&format!("{}Provider", tenant), "PluginLoaded", 2)
+        };
+        let kvs: &[(&str, &dyn log::kv::ToValue)] = &[("_meta", &meta)];
+
+        let reserved_keys = ReservedKeys::new(&kvs);
+        let mut provider_name = "Target";
+        let mut options = EventOptions::new(log::Level::Info);
+        let mut timestamp = SystemTime::now();
+        reserved_keys.apply(&mut provider_name, &mut options, &mut timestamp);
+
+        assert_eq!(provider_name, "Tenant42Provider");
+        assert_eq!(options.name, "PluginLoaded");
+        assert_eq!(options.keyword, 2);
+        assert_eq!(options.id, 3);
+
+        install_test_logger();
+
+        let event_name = String::from("PluginUnloaded");
+        info!(_meta = owned_meta::new("MyRealProviderName", event_name, 5); "Owned names");
+    }
+
+    #[test]
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    fn reserved_keys() {
+        use crate::event::{ReservedKeys, WithoutReservedKeys};
+        use log::kv::Source;
+
+        let meta = crate::event::meta {
+            provider: "MetaProviderName",
+            event_name: "MetaEvent",
+            keyword: 2,
+            id: 7,
+            ..Default::default()
+        };
+        let provider = String::from("MyRealProviderName");
+        let kvs: &[(&str, &dyn log::kv::ToValue)] = &[
+            ("_meta", &meta),
+            ("_provider", &provider.as_str()),
+            ("_keyword", &0x10u64),
+            ("_level", &300u64),
+            ("_opcode", &crate::event::opcode::STOP),
+            ("_timestamp", &1_000_000_000u64),
+            ("user_key", &1),
+        ];
+
+        let reserved_keys = ReservedKeys::new(&kvs);
+        let mut provider_name = "Target";
+        let mut options = EventOptions::new(log::Level::Info);
+        let mut timestamp = SystemTime::now();
+        reserved_keys.apply(&mut provider_name, &mut options, &mut timestamp);
+
+        assert_eq!(provider_name, "MyRealProviderName");
+        assert_eq!(options.name, "MetaEvent");
+        assert_eq!(options.keyword, 0x10);
+        assert_eq!(options.level, map_level(log::Level::Info));
+        assert_eq!(options.opcode, 2);
+        assert_eq!(options.id, 7);
+        assert_eq!(
+            timestamp,
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1)
+        );
+
+        assert_eq!(WithoutReservedKeys(&kvs).count(), 1);
+
+        install_test_logger();
+
+        info!(_provider = "MyRealProviderName", _event = "FromKeys", _keyword = 5u64, _opcode = 1u8; "Overridden with reserved keys");
     }
 }
@@ -0,0 +1,321 @@
+//! Token bucket rate limits for records, with counts of what they suppressed.
+
+use crate::logger::{unix_nanos, ExporterConfig, RateLimit, RateLimitScope};
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The records one bucket suppressed since the last summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Suppressed {
+    /// The provider, `provider:event` or `file:line` that the bucket limits.
+    pub(crate) key: String,
+    pub(crate) count: u64,
+    pub(crate) first: SystemTime,
+    pub(crate) last: SystemTime,
+}
+
+struct Bucket {
+    key: String,
+    tokens: f64,
+    updated: SystemTime,
+    suppressed: Option<Suppressed>,
+}
+
+impl Bucket {
+    /// Add the tokens earned since the bucket was last updated.
+    fn refill(&mut self, limit: &RateLimit, now: SystemTime) {
+        let elapsed = now.duration_since(self.updated).unwrap_or(Duration::ZERO);
+        if !elapsed.is_zero() {
+            let earned = if limit.refill.is_zero() {
+                f64::INFINITY
+            } else {
+                elapsed.as_secs_f64() / limit.refill.as_secs_f64()
+            };
+            self.tokens = (self.tokens + earned).min(limit.burst as f64);
+            self.updated = now;
+        }
+    }
+}
+
+/// Hashes what a bucket limits with FNV-1a, which is much cheaper than SipHash for the
+/// short strings that make up a key.
+struct KeyHasher(u64);
+
+impl Default for KeyHasher {
+    fn default() -> Self {
+        KeyHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The buckets are keyed by a [`KeyHasher`] hash already, so the maps use it as is.
+#[derive(Default)]
+struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8) | *byte as u64;
+        }
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    buckets: HashMap<u64, Bucket, BuildHasherDefault<PrehashedHasher>>,
+    // Set when the shard is full and none of its buckets can be forgotten. Until the next
+    // summary takes what they suppressed, new keys go straight to the overflow bucket.
+    full: bool,
+}
+
+/// The number of locks the buckets are spread over, so that threads logging different
+/// records rarely wait for each other.
+const SHARDS: usize = 16;
+
+/// The most buckets a shard holds. Once a shard is full, records that would need a new
+/// bucket share its overflow bucket instead, unless a bucket that has refilled with nothing
+/// to report can be forgotten. A shard is only searched for one once per summary.
+const SHARD_BUCKETS: usize = 256;
+
+/// The hash of the overflow bucket in each shard.
+const OVERFLOW: u64 = u64::MAX;
+
+/// The key that summaries report for records limited by an overflow bucket.
+pub(crate) const OVERFLOW_KEY: &str = "*";
+
+/// The buckets of a logger, keyed by a hash of what they limit.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    shards: [Mutex<Shard>; SHARDS],
+    // Unix nanoseconds when the next summary is due, or 0 if nothing was suppressed since the last one.
+    next_summary: AtomicU64,
+}
+
+impl RateLimiter {
+    /// Take a token for `record` from its bucket, or count it as suppressed if there are none left.
+    pub(crate) fn allow(
+        &self,
+        limit: &RateLimit,
+        record: &log::Record,
+        now: SystemTime,
+        config: &ExporterConfig,
+    ) -> bool {
+        if limit.scope == RateLimitScope::Callsite {
+            let mut hasher = KeyHasher::default();
+            (limit.scope, record.target(), record.file(), record.line()).hash(&mut hasher);
+            self.take_token(limit, hasher.finish(), now, || {
+                match (record.file(), record.line()) {
+                    (Some(file), Some(line)) => format!("{}:{}", file, line),
+                    _ => record.target().to_owned(),
+                }
+            })
+        } else {
+            config.resolve(record, now, |provider_name, options, _| {
+                let mut hasher = KeyHasher::default();
+                limit.scope.hash(&mut hasher);
+                match limit.scope {
+                    RateLimitScope::EventName => (provider_name, options.name).hash(&mut hasher),
+                    _ => provider_name.hash(&mut hasher),
+                }
+                self.take_token(limit, hasher.finish(), now, || match limit.scope {
+                    RateLimitScope::EventName => format!("{}:{}", provider_name, options.name),
+                    _ => provider_name.to_owned(),
+                })
+            })
+        }
+    }
+
+    /// Take a token from the bucket with this hash, creating it with `key` if it is new.
+    fn take_token(
+        &self,
+        limit: &RateLimit,
+        hash: u64,
+        now: SystemTime,
+        key: impl FnOnce() -> String,
+    ) -> bool {
+        // The low bits pick the slot within a map, so the shard comes from higher ones.
+        let mut shard = self.shards[(hash >> 32) as usize % SHARDS].lock().unwrap();
+        let shard = &mut *shard;
+        let mut hash = hash;
+        if shard.buckets.len() >= SHARD_BUCKETS && !shard.buckets.contains_key(&hash) {
+            if !shard.full {
+                // A full bucket with nothing to report is no different from a new one
+                shard.buckets.retain(|_, bucket| {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.burst as f64 || bucket.suppressed.is_some()
+                });
+                shard.full = shard.buckets.len() >= SHARD_BUCKETS;
+            }
+            if shard.full {
+                hash = OVERFLOW;
+            }
+        }
+        let bucket = shard.buckets.entry(hash).or_insert_with(|| Bucket {
+            key: if hash == OVERFLOW {
+                OVERFLOW_KEY.to_owned()
+            } else {
+                key()
+            },
+            tokens: limit.burst as f64,
+            updated: now,
+            suppressed: None,
+        });
+
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        match &mut bucket.suppressed {
+            Some(suppressed) => {
+                suppressed.count += 1;
+                suppressed.last = now;
+            }
+            None => {
+                bucket.suppressed = Some(Suppressed {
+                    key: bucket.key.clone(),
+                    count: 1,
+                    first: now,
+                    last: now,
+                });
+                let due = unix_nanos(now)
+                    .saturating_add(limit.summary_interval.as_nanos() as u64)
+                    .max(1);
+                let _ = self.next_summary.compare_exchange(
+                    0,
+                    due,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+            }
+        }
+        false
+    }
+
+    /// Whether a summary of the suppressed records is due at `now`.
+    pub(crate) fn summary_due(&self, now: SystemTime) -> bool {
+        let due = self.next_summary.load(Ordering::Relaxed);
+        due != 0 && due <= unix_nanos(now)
+    }
+
+    /// Take what each bucket suppressed since the last summary, and forget the buckets
+    /// that have refilled since they were last used.
+    pub(crate) fn take_suppressed(
+        &self,
+        limit: Option<&RateLimit>,
+        now: SystemTime,
+    ) -> Vec<Suppressed> {
+        self.next_summary.store(0, Ordering::Relaxed);
+
+        let mut suppressed = Vec::new();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.full = false;
+            suppressed.extend(
+                shard
+                    .buckets
+                    .values_mut()
+                    .filter_map(|bucket| bucket.suppressed.take()),
+            );
+
+            match limit {
+                Some(limit) => shard.buckets.retain(|_, bucket| {
+                    bucket.refill(limit, now);
+                    bucket.tokens < limit.burst as f64
+                }),
+                None => shard.buckets.clear(),
+            }
+        }
+        suppressed.sort_by(|a, b| a.key.cmp(&b.key));
+        suppressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::new_logger;
+
+    #[test]
+    fn bucket_count_is_bounded() {
+        let config = new_logger("LogEtwRateLimitBounds").into_config().unwrap();
+        let limit = RateLimit {
+            scope: RateLimitScope::Callsite,
+            ..RateLimit::per_second(1, 1)
+        };
+        let limiter = RateLimiter::default();
+        let allow = |line: u32, now: SystemTime| {
+            limiter.allow(
+                &limit,
+                &log::Record::builder()
+                    .file(Some("lib.rs"))
+                    .line(Some(line))
+                    .build(),
+                now,
+                &config,
+            )
+        };
+        let bucket_count = || {
+            limiter
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().buckets.len())
+                .sum::<usize>()
+        };
+
+        // Once the shards are full, new call sites share an overflow bucket in each
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(10);
+        let allowed = (0..10_000).filter(|line| allow(*line, now)).count();
+        assert!(bucket_count() <= SHARDS * (SHARD_BUCKETS + 1));
+        assert_eq!(allowed, bucket_count());
+
+        // Until the next summary, new call sites in a full shard share its overflow bucket
+        // without searching the shard again, even once its buckets have refilled
+        let later = now + Duration::from_secs(2);
+        assert!(allow(20_000, later));
+        assert!(!allow(20_000, later));
+        assert!(limiter.shards.iter().all(|shard| {
+            let shard = shard.lock().unwrap();
+            shard.full && shard.buckets.len() == SHARD_BUCKETS + 1
+        }));
+
+        let suppressed = limiter.take_suppressed(Some(&limit), later);
+        assert_eq!(
+            suppressed.iter().map(|s| s.count).sum::<u64>() as usize,
+            10_000 - allowed + 1
+        );
+        assert!(suppressed.iter().all(|s| s.key == OVERFLOW_KEY));
+
+        // After the summary, buckets that have refilled make room for new ones
+        for line in 20_000..20_010 {
+            assert!(allow(line, later));
+            assert!(!allow(line, later));
+        }
+        let suppressed = limiter.take_suppressed(Some(&limit), later);
+        assert_eq!(suppressed.len(), 10);
+        assert!(suppressed.iter().all(|s| s.key.starts_with("lib.rs:200")));
+    }
+}
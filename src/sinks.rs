@@ -0,0 +1,620 @@
+//! Destinations for records other than ETW and user_events.
+
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+use crate::event::WithoutReservedKeys;
+use crate::logger::{
+    current_thread_id, format_rfc3339, with_thread_name, ConsoleFormat, EventOptions,
+};
+use crate::logger::{ExporterConfig, FileRotation};
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+use log::kv::{Source, Visitor};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+/// Writes records somewhere other than the native backend.
+pub(crate) trait Sink: Send + Sync {
+    fn write_record(
+        &self,
+        provider_name: &str,
+        timestamp: SystemTime,
+        options: &EventOptions,
+        record: &log::Record,
+        exporter_config: &ExporterConfig,
+    );
+
+    fn flush(&self) {}
+}
+
+/// The value of one field of an [`Event`].
+#[derive(Debug, PartialEq)]
+#[cfg_attr(
+    not(any(feature = "kv_unstable", feature = "kv_unstable_json")),
+    allow(dead_code)
+)]
+pub(crate) enum FieldValue<'a> {
+    Str(Cow<'a, str>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+}
+
+/// The logical event that `Callsite::write_record` builds for a record,
+/// with the same name, descriptor and fields.
+pub(crate) struct Event<'a> {
+    pub(crate) provider_name: &'a str,
+    pub(crate) timestamp: SystemTime,
+    pub(crate) options: &'a EventOptions<'a>,
+    pub(crate) fields: Vec<(Cow<'static, str>, FieldValue<'a>)>,
+}
+
+impl<'a> Event<'a> {
+    pub(crate) fn new(
+        provider_name: &'a str,
+        timestamp: SystemTime,
+        options: &'a EventOptions<'a>,
+        record: &'a log::Record,
+        exporter_config: &ExporterConfig,
+    ) -> Self {
+        let field_set = &exporter_config.field_set;
+        let mut fields = Vec::new();
+
+        // The time is not a field, since sinks always show it
+        if field_set.payload {
+            let message = match record.args().as_str() {
+                Some(message) => Cow::Borrowed(message),
+                None => Cow::Owned(record.args().to_string()),
+            };
+            fields.push((Cow::Borrowed("Payload"), FieldValue::Str(message)));
+        }
+
+        #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+        add_key_values(&mut fields, record, exporter_config);
+
+        let mut add = |name: &'static str, value| fields.push((Cow::Borrowed(name), value));
+
+        if field_set.module_path {
+            if let Some(module_path) = record.module_path() {
+                add("Module Path", FieldValue::Str(Cow::Borrowed(module_path)));
+            }
+        }
+
+        if field_set.file {
+            if let Some(file) = record.file() {
+                add("File", FieldValue::Str(Cow::Borrowed(file)));
+            }
+        }
+
+        if field_set.line {
+            if let Some(line) = record.line() {
+                add("Line", FieldValue::U64(line.into()));
+            }
+        }
+
+        if field_set.target {
+            add("Target", FieldValue::Str(Cow::Borrowed(record.target())));
+        }
+
+        if field_set.level {
+            add(
+                "Level",
+                FieldValue::Str(Cow::Borrowed(record.level().as_str())),
+            );
+        }
+
+        if field_set.thread_id {
+            add("Thread ID", FieldValue::U64(current_thread_id().into()));
+        }
+
+        if field_set.thread_name {
+            if let Some(name) = with_thread_name(|name| name.map(str::to_owned)) {
+                add("Thread Name", FieldValue::Str(Cow::Owned(name)));
+            }
+        }
+
+        if field_set.process_id {
+            add("Process ID", FieldValue::U64(std::process::id().into()));
+        }
+
+        Event {
+            provider_name,
+            timestamp,
+            options,
+            fields,
+        }
+    }
+
+    /// One line of text: the timestamp, level, provider and event name, keyword and message,
+    /// followed by each other field as `name=value`.
+    pub(crate) fn write_compact(&self, level: log::Level, colors: bool, line: &mut Vec<u8>) {
+        let mut time = Vec::new();
+        line.extend_from_slice(format_rfc3339(&mut time, self.timestamp));
+
+        let (color, reset) = match level {
+            _ if !colors => ("", ""),
+            log::Level::Error => ("\x1b[31m", "\x1b[0m"),
+            log::Level::Warn => ("\x1b[33m", "\x1b[0m"),
+            log::Level::Info => ("\x1b[32m", "\x1b[0m"),
+            log::Level::Debug => ("\x1b[34m", "\x1b[0m"),
+            log::Level::Trace => ("\x1b[35m", "\x1b[0m"),
+        };
+        let _ = write!(
+            line,
+            " {}{:<5}{} {}:{} [0x{:x}]",
+            color, level, reset, self.provider_name, self.options.name, self.options.keyword
+        );
+
+        for (name, value) in &self.fields {
+            line.push(b' ');
+            if name == "Payload" {
+                if let FieldValue::Str(message) = value {
+                    line.extend_from_slice(message.as_bytes());
+                    continue;
+                }
+            }
+
+            if name.contains(' ') {
+                let _ = write!(line, "{:?}=", name);
+            } else {
+                let _ = write!(line, "{}=", name);
+            }
+            let _ = match value {
+                FieldValue::Str(value) => write!(line, "{:?}", value),
+                FieldValue::U64(value) => write!(line, "{}", value),
+                FieldValue::I64(value) => write!(line, "{}", value),
+                FieldValue::F64(value) => write!(line, "{}", value),
+                FieldValue::Bool(value) => write!(line, "{}", value),
+            };
+        }
+        line.push(b'\n');
+    }
+
+    /// One JSON object, laid out like the output of the `decode-perf` tool: the provider and
+    /// event name as `n`, then the fields, then the timestamp and descriptor under `meta`.
+    pub(crate) fn write_json(&self, line: &mut Vec<u8>) {
+        line.extend_from_slice(b"{\"n\":");
+        write_json_str(
+            line,
+            &format!("{}:{}", self.provider_name, self.options.name),
+        );
+
+        for (name, value) in &self.fields {
+            line.push(b',');
+            write_json_str(line, name);
+            line.push(b':');
+            let _ = match value {
+                FieldValue::Str(value) => {
+                    write_json_str(line, value);
+                    Ok(())
+                }
+                FieldValue::U64(value) => write!(line, "{}", value),
+                FieldValue::I64(value) => write!(line, "{}", value),
+                FieldValue::F64(value) if value.is_finite() => write!(line, "{}", value),
+                FieldValue::F64(_) => write!(line, "null"),
+                FieldValue::Bool(value) => write!(line, "{}", value),
+            };
+        }
+
+        let mut time = Vec::new();
+        line.extend_from_slice(b",\"meta\":{\"time\":\"");
+        line.extend_from_slice(format_rfc3339(&mut time, self.timestamp));
+        let options = self.options;
+        let _ = write!(
+            line,
+            "\",\"level\":{},\"keyword\":\"0x{:x}\"",
+            options.level, options.keyword
+        );
+        if options.opcode != 0 {
+            let _ = write!(line, ",\"opcode\":{}", options.opcode);
+        }
+        if options.id != 0 {
+            let _ = write!(
+                line,
+                ",\"id\":{},\"version\":{}",
+                options.id, options.version
+            );
+        }
+        if options.tag != 0 {
+            let _ = write!(line, ",\"tag\":\"0x{:x}\"", options.tag);
+        }
+        line.extend_from_slice(b"}}\n");
+    }
+}
+
+/// Add the record's key-values, except reserved ones, as `Callsite::write_record` does.
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+fn add_key_values(
+    fields: &mut Vec<(Cow<'static, str>, FieldValue)>,
+    record: &log::Record,
+    exporter_config: &ExporterConfig,
+) {
+    let key_values = WithoutReservedKeys(record.key_values());
+
+    #[cfg(feature = "kv_unstable_json")]
+    if exporter_config.json {
+        if let Ok(json) = serde_json::to_string(&log::kv::source::as_map(key_values)) {
+            fields.push((
+                Cow::Borrowed("Keys / Values"),
+                FieldValue::Str(Cow::Owned(json)),
+            ));
+        }
+        return;
+    }
+    #[cfg(not(feature = "kv_unstable_json"))]
+    let _ = exporter_config;
+
+    struct KvVisitor<'a, 'f> {
+        fields: &'a mut Vec<(Cow<'static, str>, FieldValue<'f>)>,
+    }
+    impl<'kvs> Visitor<'kvs> for KvVisitor<'_, '_> {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            let value = if let Some(value) = value.to_bool() {
+                FieldValue::Bool(value)
+            } else if let Some(value) = value.to_u64() {
+                FieldValue::U64(value)
+            } else if let Some(value) = value.to_i64() {
+                FieldValue::I64(value)
+            } else if let Some(value) = value.to_f64() {
+                FieldValue::F64(value)
+            } else {
+                FieldValue::Str(Cow::Owned(value.to_string()))
+            };
+            self.fields
+                .push((Cow::Owned(key.as_str().to_owned()), value));
+            Ok(())
+        }
+    }
+
+    let _ = key_values.visit(&mut KvVisitor { fields });
+}
+
+/// Write `value` as a quoted JSON string.
+pub(crate) fn write_json_str(out: &mut Vec<u8>, value: &str) {
+    out.push(b'"');
+    for c in value.chars() {
+        match c {
+            '"' => out.extend_from_slice(b"\\\""),
+            '\\' => out.extend_from_slice(b"\\\\"),
+            '\n' => out.extend_from_slice(b"\\n"),
+            '\r' => out.extend_from_slice(b"\\r"),
+            '\t' => out.extend_from_slice(b"\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => {
+                let mut utf8 = [0; 4];
+                out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+        }
+    }
+    out.push(b'"');
+}
+
+enum Output {
+    Stderr,
+    File(File),
+}
+
+/// Writes each record as a line of text, for people rather than trace sessions.
+pub(crate) struct TextSink {
+    output: Mutex<Output>,
+    format: ConsoleFormat,
+    colors: bool,
+}
+
+impl TextSink {
+    pub(crate) fn stderr(format: ConsoleFormat, colors: bool) -> Self {
+        TextSink {
+            output: Mutex::new(Output::Stderr),
+            format,
+            colors,
+        }
+    }
+
+    /// Append to the file at `path`, creating it if needed.
+    pub(crate) fn file(path: &Path, format: ConsoleFormat) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(TextSink {
+            output: Mutex::new(Output::File(file)),
+            format,
+            colors: false,
+        })
+    }
+}
+
+impl Sink for TextSink {
+    fn write_record(
+        &self,
+        provider_name: &str,
+        timestamp: SystemTime,
+        options: &EventOptions,
+        record: &log::Record,
+        exporter_config: &ExporterConfig,
+    ) {
+        let event = Event::new(provider_name, timestamp, options, record, exporter_config);
+        let mut line = Vec::with_capacity(256);
+        match self.format {
+            ConsoleFormat::Compact => event.write_compact(record.level(), self.colors, &mut line),
+            ConsoleFormat::Json => event.write_json(&mut line),
+        }
+
+        // Each line is written at once, so lines from different threads are not interleaved
+        let _ = match &mut *self.output.lock().unwrap() {
+            Output::Stderr => std::io::stderr().write_all(&line),
+            Output::File(file) => file.write_all(&line),
+        };
+    }
+
+    fn flush(&self) {
+        let _ = match &mut *self.output.lock().unwrap() {
+            Output::Stderr => std::io::stderr().flush(),
+            Output::File(file) => file.flush(),
+        };
+    }
+}
+
+struct OpenFile {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl OpenFile {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(OpenFile {
+            size: file.metadata()?.len(),
+            file,
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// Writes each record as a JSON object on its own line, rotating the file as configured.
+pub(crate) struct JsonFileSink {
+    path: PathBuf,
+    rotation: FileRotation,
+    // None if the file could not be reopened after rotating it. The next write tries again.
+    file: Mutex<Option<OpenFile>>,
+}
+
+impl JsonFileSink {
+    /// Append to the file at `path`, creating it if needed.
+    pub(crate) fn new(path: &Path, rotation: FileRotation) -> std::io::Result<Self> {
+        Ok(JsonFileSink {
+            path: path.to_owned(),
+            rotation,
+            file: Mutex::new(Some(OpenFile::open(path)?)),
+        })
+    }
+
+    /// Whether this sink was opened with the same settings, so that it can be kept.
+    pub(crate) fn is_same(&self, path: &Path, rotation: &FileRotation) -> bool {
+        self.path == path && self.rotation == *rotation
+    }
+
+    fn needs_rotation(&self, file: &OpenFile, len: usize) -> bool {
+        // A line larger than `max_bytes` still goes into a file of its own
+        file.size != 0
+            && (self
+                .rotation
+                .max_bytes
+                .map_or(false, |max| file.size + len as u64 > max)
+                || self
+                    .rotation
+                    .max_age
+                    .map_or(false, |max| file.opened.elapsed() >= max))
+    }
+
+    /// Rename the file to `<path>.1`, after renaming older files to `<path>.2` and so on,
+    /// deleting those beyond `keep`, and open a new file.
+    fn rotate(&self, file: &mut Option<OpenFile>) {
+        // Open files cannot be renamed on Windows
+        *file = None;
+
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            let _ = std::fs::remove_file(&self.path);
+        } else {
+            let _ = std::fs::remove_file(rotated_path(&self.path, keep));
+            for n in (1..keep).rev() {
+                let _ =
+                    std::fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1));
+            }
+            let _ = std::fs::rename(&self.path, rotated_path(&self.path, 1));
+        }
+
+        *file = OpenFile::open(&self.path).ok();
+    }
+}
+
+/// The path of the `n`th most recent rotated file.
+pub(crate) fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+impl Sink for JsonFileSink {
+    fn write_record(
+        &self,
+        provider_name: &str,
+        timestamp: SystemTime,
+        options: &EventOptions,
+        record: &log::Record,
+        exporter_config: &ExporterConfig,
+    ) {
+        let event = Event::new(provider_name, timestamp, options, record, exporter_config);
+        let mut line = Vec::with_capacity(256);
+        event.write_json(&mut line);
+
+        let mut file = self.file.lock().unwrap();
+        match &*file {
+            Some(open) if self.needs_rotation(open, line.len()) => self.rotate(&mut file),
+            Some(_) => (),
+            None => *file = OpenFile::open(&self.path).ok(),
+        }
+
+        if let Some(open) = &mut *file {
+            if open.file.write_all(&line).is_ok() {
+                open.size += line.len() as u64;
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(open) = &mut *self.file.lock().unwrap() {
+            let _ = open.file.flush();
+        }
+    }
+}
+
+// The sink is shared by the configurations that write to its file
+impl Sink for Arc<JsonFileSink> {
+    fn write_record(
+        &self,
+        provider_name: &str,
+        timestamp: SystemTime,
+        options: &EventOptions,
+        record: &log::Record,
+        exporter_config: &ExporterConfig,
+    ) {
+        (**self).write_record(provider_name, timestamp, options, record, exporter_config);
+    }
+
+    fn flush(&self) {
+        (**self).flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::{new_logger, FieldSet, FileRotation};
+
+    fn render(format: ConsoleFormat, colors: bool, record: &log::Record) -> String {
+        let config = new_logger("LogEtwSink")
+            .with_field_set(FieldSet {
+                module_path: false,
+                file: false,
+                ..FieldSet::default()
+            })
+            .into_config()
+            .unwrap();
+        let mut options = EventOptions::new(record.level());
+        options.name = "MyEvent";
+        options.opcode = 1;
+        let timestamp = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1500);
+
+        let event = Event::new("MyProvider", timestamp, &options, record, &config);
+        let mut line = Vec::new();
+        match format {
+            ConsoleFormat::Compact => event.write_compact(record.level(), colors, &mut line),
+            ConsoleFormat::Json => event.write_json(&mut line),
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[test]
+    fn text_formats() {
+        let render_all = |record: &log::Record| {
+            [
+                render(ConsoleFormat::Compact, false, record),
+                render(ConsoleFormat::Compact, true, record),
+                render(ConsoleFormat::Json, true, record),
+            ]
+        };
+        let [compact, colored, json] = render_all(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .args(format_args!("Said \"{}\"", "hi"))
+                .line(Some(7))
+                .build(),
+        );
+
+        assert_eq!(
+            compact,
+            "1970-01-01T00:00:01.500000000Z WARN  MyProvider:MyEvent [0x1] Said \"hi\" Line=7\n"
+        );
+        assert_eq!(
+            colored,
+            "1970-01-01T00:00:01.500000000Z \x1b[33mWARN \x1b[0m MyProvider:MyEvent [0x1] Said \"hi\" Line=7\n"
+        );
+        // Colors would make the line unparseable
+        assert_eq!(
+            json,
+            "{\"n\":\"MyProvider:MyEvent\",\"Payload\":\"Said \\\"hi\\\"\",\"Line\":7,\
+             \"meta\":{\"time\":\"1970-01-01T00:00:01.500000000Z\",\"level\":3,\"keyword\":\"0x1\",\"opcode\":1}}\n"
+        );
+    }
+
+    #[test]
+    fn json_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("log-etw-rotation-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("events.jsonl");
+
+        let config = new_logger("LogEtwSink").into_config().unwrap();
+        let rotation = FileRotation {
+            max_bytes: Some(1),
+            max_age: None,
+            keep: 2,
+        };
+        let sink = JsonFileSink::new(&path, rotation).unwrap();
+        for n in 0..4 {
+            sink.write_record(
+                "MyProvider",
+                SystemTime::UNIX_EPOCH,
+                &EventOptions::new(log::Level::Info),
+                &log::Record::builder()
+                    .args(format_args!("Event {}", n))
+                    .build(),
+                &config,
+            );
+        }
+
+        // Every line is larger than the limit, so each is in a file of its own,
+        // and only the two most recent old files are kept
+        for (path, n) in [
+            (path.clone(), 3),
+            (rotated_path(&path, 1), 2),
+            (rotated_path(&path, 2), 1),
+        ] {
+            let contents = std::fs::read_to_string(path).unwrap();
+            assert_eq!(contents.lines().count(), 1);
+            assert!(contents.starts_with("{\"n\":\"MyProvider:Event\","));
+            assert!(contents.contains(&format!("\"Payload\":\"Event {}\"", n)));
+        }
+        assert!(!rotated_path(&path, 3).exists());
+
+        drop(sink);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    #[test]
+    fn text_key_values() {
+        let line = render(
+            ConsoleFormat::Compact,
+            false,
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .args(format_args!("Message"))
+                .key_values(&("count", 3u32))
+                .build(),
+        );
+        assert_eq!(
+            line,
+            "1970-01-01T00:00:01.500000000Z INFO  MyProvider:MyEvent [0x1] Message count=3\n"
+        );
+    }
+}
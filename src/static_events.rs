@@ -0,0 +1,238 @@
+//! Compile-time events for latency-critical code paths.
+//!
+//! Records passed through the `log` crate are encoded at runtime, so every event
+//! rebuilds its metadata (event name, field names and field types). The macros in this
+//! module instead expand to the static `write_event!` macros from the `tracelogging`
+//! (Windows) and `eventheader` (Linux) crates, which encode the metadata at compile time
+//! and skip evaluating the field values entirely when no session is listening.
+//!
+//! Because those macros refer to their own crates by absolute path, a crate that uses
+//! these macros must also depend on `tracelogging` and `eventheader` directly.
+//!
+//! ```ignore
+//! log_etw::define_provider!(MY_PROVIDER, "MyCompany_MyComponent");
+//!
+//! // Safety: If this is a shared object, you MUST call MY_PROVIDER.unregister() before unload.
+//! unsafe { MY_PROVIDER.register(); }
+//!
+//! let status = 200u32;
+//! let path = "/index.html";
+//! log_etw::etw_info!(provider: MY_PROVIDER, "RequestCompleted", status = status, path = path);
+//!
+//! log_etw::etw_warn!(provider: MY_PROVIDER, "CacheEvicted", keyword: 0x2,
+//!     reason = "capacity",
+//!     u64("Entries", &1024u64),
+//! );
+//!
+//! MY_PROVIDER.unregister();
+//! ```
+//!
+//! Fields written as `name = value`, like the key-values of `log`, are formatted with
+//! [`Display`](std::fmt::Display) into a string field. To keep a value's type in the event,
+//! use the syntax of the underlying crates instead, such as `u32("Name", &value)`.
+//! The keyword defaults to 1, the same keyword used for events logged through the `log`
+//! crate, and levels are mapped the same way as [`log::Level`].
+//!
+//! Field values are only evaluated, and only formatted, while a session is listening.
+
+/// Define a static provider for use with [`etw_event!`](crate::etw_event) and the related macros.
+///
+/// On Windows this expands to `tracelogging::define_provider!`, and on Linux to
+/// `eventheader::define_provider!`. Any options after the provider name are passed through.
+/// The provider must be registered with `unsafe { PROVIDER.register(); }` before use.
+#[macro_export]
+macro_rules! define_provider {
+    ($provider:ident, $name:tt $(, $($options:tt)*)?) => {
+        #[cfg(target_os = "windows")]
+        $crate::__private::tracelogging::define_provider!($provider, $name $(, $($options)*)?);
+        #[cfg(target_os = "linux")]
+        $crate::__private::eventheader::define_provider!($provider, $name $(, $($options)*)?);
+    };
+}
+
+/// Write a compile-time event at the given [`log::Level`].
+///
+/// `etw_event!(provider: PROVIDER, level: Warn, "EventName", keyword: 0x2, fields...)`
+///
+/// The keyword is optional. Fields are either `name = value` or a typed field of the
+/// underlying `write_event!`, such as `u32("Name", &value)`.
+/// Returns the result code from the underlying `write_event!`.
+#[macro_export]
+macro_rules! etw_event {
+    (provider: $provider:ident, level: Error, $($rest:tt)*) => {
+        $crate::__etw_event!(@level(Error) $provider, $($rest)*)
+    };
+    (provider: $provider:ident, level: Warn, $($rest:tt)*) => {
+        $crate::__etw_event!(@level(Warning) $provider, $($rest)*)
+    };
+    (provider: $provider:ident, level: Info, $($rest:tt)*) => {
+        $crate::__etw_event!(@level(Informational) $provider, $($rest)*)
+    };
+    (provider: $provider:ident, level: Debug, $($rest:tt)*) => {
+        $crate::__etw_event!(@level(Verbose) $provider, $($rest)*)
+    };
+    (provider: $provider:ident, level: Trace, $($rest:tt)*) => {
+        $crate::__etw_event!(@level(6) $provider, $($rest)*)
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __etw_event {
+    (@level($($level:tt)*) $provider:ident, $name:tt, keyword: $keyword:expr $(, $($fields:tt)*)?) => {{
+        #[cfg(target_os = "windows")]
+        let result = $crate::__private::__static_event!(
+            $crate, $crate::__private::tracelogging::write_event,
+            ($provider, $name, level($($level)*), keyword($keyword)) $(, $($fields)*)?
+        );
+        #[cfg(target_os = "linux")]
+        #[allow(unknown_lints, static_mut_refs)] // The expansion refers to a `static mut` tracepoint.
+        let result = $crate::__private::__static_event!(
+            $crate, $crate::__private::eventheader::write_event,
+            ($provider, $name, level($($level)*), keyword($keyword)) $(, $($fields)*)?
+        );
+        result
+    }};
+    (@level($($level:tt)*) $provider:ident, $name:tt $(, $($fields:tt)*)?) => {
+        $crate::__etw_event!(@level($($level)*) $provider, $name, keyword: 1 $(, $($fields)*)?)
+    };
+}
+
+/// Write a compile-time event at [`log::Level::Error`]. See [`etw_event!`](crate::etw_event).
+#[macro_export]
+macro_rules! etw_error {
+    (provider: $provider:ident, $($rest:tt)*) => {
+        $crate::etw_event!(provider: $provider, level: Error, $($rest)*)
+    };
+}
+
+/// Write a compile-time event at [`log::Level::Warn`]. See [`etw_event!`](crate::etw_event).
+#[macro_export]
+macro_rules! etw_warn {
+    (provider: $provider:ident, $($rest:tt)*) => {
+        $crate::etw_event!(provider: $provider, level: Warn, $($rest)*)
+    };
+}
+
+/// Write a compile-time event at [`log::Level::Info`]. See [`etw_event!`](crate::etw_event).
+#[macro_export]
+macro_rules! etw_info {
+    (provider: $provider:ident, $($rest:tt)*) => {
+        $crate::etw_event!(provider: $provider, level: Info, $($rest)*)
+    };
+}
+
+/// Write a compile-time event at [`log::Level::Debug`]. See [`etw_event!`](crate::etw_event).
+#[macro_export]
+macro_rules! etw_debug {
+    (provider: $provider:ident, $($rest:tt)*) => {
+        $crate::etw_event!(provider: $provider, level: Debug, $($rest)*)
+    };
+}
+
+/// Write a compile-time event at [`log::Level::Trace`]. See [`etw_event!`](crate::etw_event).
+#[macro_export]
+macro_rules! etw_trace {
+    (provider: $provider:ident, $($rest:tt)*) => {
+        $crate::etw_event!(provider: $provider, level: Trace, $($rest)*)
+    };
+}
+
+/// A `name = value` field, formatted on the stack unless it is long.
+#[doc(hidden)]
+pub struct DisplayField {
+    inline: [u8; 128],
+    len: usize,
+    spilled: Option<String>,
+}
+
+impl DisplayField {
+    pub fn new(value: &dyn std::fmt::Display) -> Self {
+        use std::fmt::Write;
+
+        let mut field = DisplayField {
+            inline: [0; 128],
+            len: 0,
+            spilled: None,
+        };
+        let _ = write!(field, "{}", value);
+        field
+    }
+}
+
+impl std::fmt::Write for DisplayField {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        if let Some(spilled) = &mut self.spilled {
+            spilled.push_str(s);
+        } else if let Some(inline) = self.inline.get_mut(self.len..self.len + s.len()) {
+            inline.copy_from_slice(s.as_bytes());
+            self.len += s.len();
+        } else {
+            let mut spilled = String::with_capacity((self.len + s.len()) * 2);
+            // Only whole `str`s are copied into the buffer
+            spilled.push_str(std::str::from_utf8(&self.inline[..self.len]).unwrap_or_default());
+            spilled.push_str(s);
+            self.spilled = Some(spilled);
+        }
+        Ok(())
+    }
+}
+
+impl AsRef<[u8]> for DisplayField {
+    fn as_ref(&self) -> &[u8] {
+        match &self.spilled {
+            Some(spilled) => spilled.as_bytes(),
+            None => &self.inline[..self.len],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::define_provider!(TEST_PROVIDER, "LogEtwStaticEvents");
+
+    use super::DisplayField;
+    use std::cell::Cell;
+
+    #[test]
+    fn static_events() {
+        unsafe {
+            TEST_PROVIDER.register();
+        }
+
+        // Counts the field values that were evaluated
+        let evaluated = Cell::new(0);
+        let value = |v: u32| {
+            evaluated.set(evaluated.get() + 1);
+            v
+        };
+
+        let path = "/index.html";
+        crate::etw_info!(provider: TEST_PROVIDER, "RequestCompleted",
+            status = value(200),
+            path = path,
+            u32("Latency", &value(5)),
+        );
+        crate::etw_warn!(provider: TEST_PROVIDER, "CacheEvicted", keyword: 0x2, reason = "capacity");
+        crate::etw_error!(provider: TEST_PROVIDER, "Failed", i32("Code", &-1), retry = value(1) == 1);
+        crate::etw_debug!(provider: TEST_PROVIDER, "Debug", keyword: 0x4, u8("Byte", &1u8));
+        crate::etw_trace!(provider: TEST_PROVIDER, "Trace");
+
+        // No session is listening, so none of the values were evaluated
+        assert_eq!(evaluated.get(), 0);
+
+        TEST_PROVIDER.unregister();
+    }
+
+    #[test]
+    fn display_field() {
+        assert_eq!(DisplayField::new(&200u32).as_ref(), b"200");
+        assert_eq!(DisplayField::new(&"").as_ref(), b"");
+
+        // Longer values move to the heap
+        let long = "x".repeat(100);
+        let field = DisplayField::new(&format_args!("{}é{}", long, long));
+        assert_eq!(field.as_ref(), format!("{}é{}", long, long).as_bytes());
+        assert!(field.spilled.is_some());
+    }
+}
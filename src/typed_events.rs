@@ -0,0 +1,308 @@
+//! Strongly typed events.
+//!
+//! Events that are logged from many places can be described once as a struct, so that
+//! their schema cannot drift between call sites. With the `derive` feature enabled:
+//!
+//! ```ignore
+//! use log_etw::{log_event, LogEtwEvent};
+//!
+//! #[derive(LogEtwEvent)]
+//! #[etw(provider = "MyCompany_MyService", name = "RequestCompleted", keyword = 0x2)]
+//! struct RequestCompleted<'a> {
+//!     path: &'a str,
+//!     #[etw(format = "hex")]
+//!     status: u32,
+//! }
+//!
+//! log_event!(RequestCompleted { path: "/index.html", status: 200 });
+//! ```
+//!
+//! Typed events are written through the same providers as records logged through the
+//! `log` crate, so a provider name used by both is only registered once.
+
+use crate::logger::{
+    get_or_create_cached_provider, map_level, typed_event_policy, ProviderWrapper,
+};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use eventheader_dynamic::EventBuilder;
+#[cfg(target_os = "windows")]
+use tracelogging_dynamic::EventBuilder;
+
+/// An event with a fixed schema, usually implemented with `#[derive(LogEtwEvent)]`.
+pub trait LogEtwEvent {
+    /// The name of the provider that writes this event.
+    const PROVIDER: &'static str;
+    /// The event name.
+    const NAME: &'static str;
+    /// The event level, mapped to ETW and EventHeader levels the same way as other log records.
+    const LEVEL: log::Level;
+    /// The event keyword.
+    const KEYWORD: u64;
+    /// The event opcode. 0 is Info, 1 is Start and 2 is Stop.
+    const OPCODE: u8;
+
+    /// Add the fields of this event, in schema order.
+    fn add_fields(&self, writer: &mut EventWriter);
+
+    /// The provider of this type, kept after it is first looked up.
+    /// `#[derive(LogEtwEvent)]` gives each type its own, so that writing an event
+    /// does not look the provider up by name.
+    #[doc(hidden)]
+    fn provider_slot() -> Option<&'static ProviderSlot> {
+        None
+    }
+
+    /// Write this event if any session is listening for it. Like other records, the event is
+    /// dropped if its level is filtered out by the installed logger, or the logger has been
+    /// shut down.
+    fn log_event(&self)
+    where
+        Self: Sized,
+    {
+        let policy = match typed_event_policy(Self::LEVEL) {
+            Some(policy) => policy,
+            None => return,
+        };
+
+        let level = map_level(Self::LEVEL);
+        let create =
+            || get_or_create_cached_provider(Self::PROVIDER, None, None, &[(level, Self::KEYWORD)]);
+        let looked_up;
+        let provider = match Self::provider_slot() {
+            Some(slot) => slot.get_or_init(create),
+            None => {
+                looked_up = create();
+                &looked_up
+            }
+        };
+        provider.as_ref().write_typed(
+            Self::NAME,
+            level,
+            Self::KEYWORD,
+            Self::OPCODE,
+            policy,
+            |writer| self.add_fields(writer),
+        );
+    }
+}
+
+/// Where a [`LogEtwEvent`] type keeps its provider. Declared by `#[derive(LogEtwEvent)]`.
+#[doc(hidden)]
+pub struct ProviderSlot(AtomicPtr<Pin<Arc<ProviderWrapper>>>);
+
+impl ProviderSlot {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        ProviderSlot(AtomicPtr::new(std::ptr::null_mut()))
+    }
+
+    fn get_or_init(
+        &self,
+        init: impl FnOnce() -> Pin<Arc<ProviderWrapper>>,
+    ) -> &Pin<Arc<ProviderWrapper>> {
+        let mut provider = self.0.load(Ordering::Acquire);
+        if provider.is_null() {
+            let new = Box::into_raw(Box::new(init()));
+            provider = match self.0.compare_exchange(
+                std::ptr::null_mut(),
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                Err(existing) => {
+                    // Another thread got there first, and nothing else refers to this one
+                    drop(unsafe { Box::from_raw(new) });
+                    existing
+                }
+            };
+        }
+
+        // Slots are statics, and the provider in one is never replaced or freed
+        unsafe { &*provider }
+    }
+}
+
+/// Write a [`LogEtwEvent`].
+#[macro_export]
+macro_rules! log_event {
+    ($event:expr) => {
+        $crate::typed_events::LogEtwEvent::log_event(&$event)
+    };
+}
+
+/// How a field is displayed by event decoders.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldFormat {
+    /// Decimal for numbers, UTF-8 for strings.
+    #[default]
+    Default,
+    /// Hexadecimal. Only applies to integers.
+    Hex,
+    /// A process or thread ID. Only applies to 32-bit integers.
+    Pid,
+    /// A JSON document. Only applies to strings.
+    Json,
+    /// An XML document. Only applies to strings.
+    Xml,
+}
+
+impl FieldFormat {
+    #[cfg(target_os = "windows")]
+    fn out_type(self, default: tracelogging::OutType) -> tracelogging::OutType {
+        use tracelogging::OutType;
+        match self {
+            FieldFormat::Default => default,
+            FieldFormat::Hex => OutType::Hex,
+            FieldFormat::Pid => OutType::Pid,
+            FieldFormat::Json => OutType::Json,
+            FieldFormat::Xml => OutType::Xml,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn field_format(self, default: eventheader::FieldFormat) -> eventheader::FieldFormat {
+        use eventheader::FieldFormat as Format;
+        match self {
+            FieldFormat::Default => default,
+            FieldFormat::Hex => Format::HexInt,
+            FieldFormat::Pid => Format::Pid,
+            FieldFormat::Json => Format::StringJson,
+            FieldFormat::Xml => Format::StringXml,
+        }
+    }
+}
+
+/// Adds fields to an event that is being built.
+pub struct EventWriter<'a> {
+    eb: &'a mut EventBuilder,
+}
+
+macro_rules! add_value_methods {
+    ($($method:ident: $ty:ty => $win:ident($win_default:ident), $linux_default:ident;)*) => {
+        $(
+            #[doc = concat!("Add a `", stringify!($ty), "` field.")]
+            pub fn $method(&mut self, name: &str, value: $ty, format: FieldFormat) {
+                #[cfg(target_os = "windows")]
+                self.eb.$win(name, value, format.out_type(tracelogging::OutType::$win_default), 0);
+                #[cfg(target_os = "linux")]
+                self.eb.add_value(
+                    name,
+                    value,
+                    format.field_format(eventheader::FieldFormat::$linux_default),
+                    0,
+                );
+            }
+        )*
+    };
+}
+
+impl<'a> EventWriter<'a> {
+    pub(crate) fn new(eb: &'a mut EventBuilder) -> Self {
+        EventWriter { eb }
+    }
+
+    add_value_methods! {
+        add_u8: u8 => add_u8(Default), Default;
+        add_u16: u16 => add_u16(Default), Default;
+        add_u32: u32 => add_u32(Default), Default;
+        add_u64: u64 => add_u64(Default), Default;
+        add_usize: usize => add_usize(Default), Default;
+        add_i8: i8 => add_i8(Default), SignedInt;
+        add_i16: i16 => add_i16(Default), SignedInt;
+        add_i32: i32 => add_i32(Default), SignedInt;
+        add_i64: i64 => add_i64(Default), SignedInt;
+        add_isize: isize => add_isize(Default), SignedInt;
+        add_f32: f32 => add_f32(Default), Float;
+        add_f64: f64 => add_f64(Default), Float;
+    }
+
+    /// Add a `bool` field.
+    pub fn add_bool(&mut self, name: &str, value: bool, format: FieldFormat) {
+        #[cfg(target_os = "windows")]
+        self.eb.add_bool32(
+            name,
+            value as i32,
+            format.out_type(tracelogging::OutType::Default),
+            0,
+        );
+        #[cfg(target_os = "linux")]
+        self.eb.add_value(
+            name,
+            value as i32,
+            format.field_format(eventheader::FieldFormat::Boolean),
+            0,
+        );
+    }
+
+    /// Add a UTF-8 string field.
+    pub fn add_str(&mut self, name: &str, value: &str, format: FieldFormat) {
+        #[cfg(target_os = "windows")]
+        self.eb
+            .add_str8(name, value, format.out_type(tracelogging::OutType::Utf8), 0);
+        #[cfg(target_os = "linux")]
+        self.eb.add_str(
+            name,
+            value,
+            format.field_format(eventheader::FieldFormat::Default),
+            0,
+        );
+    }
+}
+
+/// A value that can be a field of a [`LogEtwEvent`].
+/// Implement this for your own types to use them in derived events.
+pub trait EventField {
+    /// Add `self` to the event as a field called `name`.
+    fn add_field(&self, name: &str, format: FieldFormat, writer: &mut EventWriter);
+}
+
+macro_rules! impl_event_field {
+    ($($ty:ty => $method:ident,)*) => {
+        $(
+            impl EventField for $ty {
+                fn add_field(&self, name: &str, format: FieldFormat, writer: &mut EventWriter) {
+                    writer.$method(name, *self, format);
+                }
+            }
+        )*
+    };
+}
+
+impl_event_field! {
+    u8 => add_u8,
+    u16 => add_u16,
+    u32 => add_u32,
+    u64 => add_u64,
+    usize => add_usize,
+    i8 => add_i8,
+    i16 => add_i16,
+    i32 => add_i32,
+    i64 => add_i64,
+    isize => add_isize,
+    f32 => add_f32,
+    f64 => add_f64,
+    bool => add_bool,
+}
+
+impl EventField for str {
+    fn add_field(&self, name: &str, format: FieldFormat, writer: &mut EventWriter) {
+        writer.add_str(name, self, format);
+    }
+}
+
+impl EventField for String {
+    fn add_field(&self, name: &str, format: FieldFormat, writer: &mut EventWriter) {
+        writer.add_str(name, self, format);
+    }
+}
+
+impl<T: EventField + ?Sized> EventField for &T {
+    fn add_field(&self, name: &str, format: FieldFormat, writer: &mut EventWriter) {
+        (**self).add_field(name, format, writer);
+    }
+}
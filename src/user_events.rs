@@ -1,14 +1,22 @@
-use crate::logger::{map_level, ExporterConfig, ProviderWrapper};
+use crate::buffers::{with_event_buffers, EventBuffers};
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+use crate::event::WithoutReservedKeys;
+#[cfg(feature = "spans")]
+use crate::logger::active_span_ids;
+use crate::logger::{
+    current_thread_id, filetime, format_message, format_rfc3339, unix_nanos, with_thread_name,
+    Callsite, EventOptions, ExporterConfig, ProviderWrapper, RegistrationPolicy, TimestampFormat,
+};
+use crate::typed_events::EventWriter;
 use eventheader::*;
+#[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
 use eventheader_dynamic::EventBuilder;
 #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
-use log::kv::{source, value::Visit, Visitor};
-use std::{cell::RefCell, pin::Pin, sync::Arc, time::SystemTime};
-
-thread_local! {static EBW: std::cell::RefCell<EventBuilder>  = RefCell::new(EventBuilder::new());}
+use log::kv::{value::Visit, Source, Visitor};
+use std::{pin::Pin, sync::Arc, time::SystemTime};
 
 impl ProviderWrapper {
-    fn find_set(
+    pub(crate) fn find_set(
         self: Pin<&Self>,
         level: eventheader_dynamic::Level,
         keyword: u64,
@@ -16,7 +24,7 @@ impl ProviderWrapper {
         self.get_provider().read().unwrap().find_set(level, keyword)
     }
 
-    fn register_set(
+    pub(crate) fn register_set(
         self: Pin<&Self>,
         level: eventheader_dynamic::Level,
         keyword: u64,
@@ -27,322 +35,364 @@ impl ProviderWrapper {
             .register_set(level, keyword)
     }
 
-    pub(crate) fn write_record(
+    pub(crate) fn write_typed(
         self: Pin<&Self>,
-        timestamp: SystemTime,
         event_name: &str,
+        level: u8,
         keyword: u64,
-        record: &log::Record,
-        exporter_config: &ExporterConfig,
+        opcode: u8,
+        registration_policy: RegistrationPolicy,
+        add_fields: impl FnOnce(&mut EventWriter),
     ) {
-        let level = map_level(record.level());
+        // The provider may have been created without this event's set, for example by a record
+        let es = match self.find_set(level.into(), keyword) {
+            Some(es) if es.enabled() => es,
+            None if registration_policy == RegistrationPolicy::Lazy => {
+                let es = self.register_set(level.into(), keyword);
+                if !es.enabled() {
+                    self.counters.disabled();
+                    return;
+                }
+                es
+            }
+            _ => {
+                self.counters.disabled();
+                return;
+            }
+        };
 
-        if !self.enabled(level, keyword) {
-            return;
-        }
+        with_event_buffers(|buffers| {
+            let eb = &mut buffers.eb;
 
-        let es = if let Some(es) = self.find_set(level.into(), keyword) {
-            es
-        } else {
-            self.register_set(level.into(), keyword)
-        };
+            eb.reset(event_name, 0);
+            eb.opcode(Opcode::from_int(opcode));
 
-        EBW.with(|eb| {
-            let mut eb = eb.borrow_mut();
+            add_fields(&mut EventWriter::new(eb));
+
+            self.counters.write_result(eb.write(&es, None, None) as u32);
+        })
+    }
+}
+
+impl Callsite {
+    pub(crate) fn write_record(
+        &self,
+        timestamp: SystemTime,
+        options: &EventOptions,
+        record: &log::Record,
+        exporter_config: &ExporterConfig,
+    ) {
+        let es = match self.event_set(options.level, options.keyword, exporter_config) {
+            Some(es) if es.enabled() => es,
+            _ => {
+                self.provider.counters.disabled();
+                return;
+            }
+        };
 
-            if !exporter_config.common_schema {
-                eb.reset(&event_name, 0);
-                eb.opcode(Opcode::Info);
+        with_event_buffers(|buffers| {
+            build_record(buffers, timestamp, options, record, exporter_config);
+            self.provider
+                .counters
+                .write_result(buffers.eb.write(&es, None, None) as u32);
+        })
+    }
+}
 
+/// Build the event for a record, without writing it.
+pub(crate) fn build_record(
+    buffers: &mut EventBuffers,
+    timestamp: SystemTime,
+    options: &EventOptions,
+    record: &log::Record,
+    exporter_config: &ExporterConfig,
+) {
+    let EventBuffers { eb, buffer } = buffers;
+
+    if !exporter_config.common_schema {
+        eb.reset(options.name, options.tag as u16);
+        eb.opcode(Opcode::from_int(options.opcode));
+        eb.id_version(options.id, options.version);
+
+        let field_set = &exporter_config.field_set;
+
+        match exporter_config.timestamp_format {
+            _ if !field_set.time => (),
+            TimestampFormat::FileTime => {
+                eb.add_value("time", filetime(timestamp), FieldFormat::SignedInt, 0);
+            }
+            TimestampFormat::UnixNanos => {
+                eb.add_value("time", unix_nanos(timestamp), FieldFormat::Default, 0);
+            }
+            TimestampFormat::Rfc3339 => {
+                let time = format_rfc3339(buffer, timestamp);
+                eb.add_str("time", time, FieldFormat::Default, 0);
+            }
+            TimestampFormat::SystemTime => {
                 eb.add_value(
                     "time",
-                    timestamp
-                        .duration_since(std::time::SystemTime::UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs(),
+                    unix_nanos(timestamp) / 1_000_000_000,
                     FieldFormat::Time,
                     0,
                 );
+            }
+            TimestampFormat::None => (),
+        }
 
-                let payload = format!("{}", record.args());
-                eb.add_str("Payload", payload, FieldFormat::Default, 0);
+        if field_set.payload {
+            eb.add_str(
+                "Payload",
+                format_message(record.args(), buffer),
+                FieldFormat::Default,
+                0,
+            );
+        }
 
-                #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
-                {
-                    if cfg!(feature = "kv_unstable_json") && exporter_config.json {
-                        if let Ok(json) =
-                            serde_json::to_string(&source::as_map(record.key_values()))
-                        {
-                            eb.add_str("Keys / Values", json, FieldFormat::Default, 0);
-                        }
-                    } else {
-                        struct ValueVisitor<'v, 'a> {
-                            key_name: &'v str,
-                            eb: &'a mut EventBuilder,
-                        }
-                        impl<'v, 'a> Visit<'v> for ValueVisitor<'v, 'a> {
-                            fn visit_any(
-                                &mut self,
-                                value: log::kv::Value,
-                            ) -> Result<(), log::kv::Error> {
-                                self.eb.add_str(
-                                    self.key_name,
-                                    value.to_string(),
-                                    FieldFormat::Default,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error> {
-                                self.eb.add_value(
-                                    self.key_name,
-                                    value as i32,
-                                    FieldFormat::Boolean,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_borrowed_str(
-                                &mut self,
-                                value: &'v str,
-                            ) -> Result<(), log::kv::Error> {
-                                self.eb.add_str(
-                                    self.key_name,
-                                    value,
-                                    FieldFormat::Default,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_str(&mut self, value: &str) -> Result<(), log::kv::Error> {
-                                self.eb.add_str(
-                                    self.key_name,
-                                    value,
-                                    FieldFormat::Default,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_char(&mut self, value: char) -> Result<(), log::kv::Error> {
-                                self.eb.add_value(
-                                    self.key_name,
-                                    value as u8,
-                                    FieldFormat::String8,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_f64(&mut self, value: f64) -> Result<(), log::kv::Error> {
-                                self.eb.add_value(
-                                    self.key_name,
-                                    value,
-                                    FieldFormat::Float,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_i128(&mut self, value: i128) -> Result<(), log::kv::Error> {
-                                unsafe {
-                                    self.eb.add_value_sequence(
-                                        self.key_name,
-                                        core::slice::from_raw_parts(
-                                            &value.to_le_bytes() as *const u8 as *const u64,
-                                            2,
-                                        ),
-                                        FieldFormat::HexInt,
-                                        0,
-                                    );
-                                }
-                                Ok(())
-                            }
-
-                            fn visit_u128(&mut self, value: u128) -> Result<(), log::kv::Error> {
-                                unsafe {
-                                    self.eb.add_value_sequence(
-                                        self.key_name,
-                                        core::slice::from_raw_parts(
-                                            &value.to_le_bytes() as *const u8 as *const u64,
-                                            2,
-                                        ),
-                                        FieldFormat::HexInt,
-                                        0,
-                                    );
-                                }
-                                Ok(())
-                            }
-
-                            fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
-                                self.eb.add_value(
-                                    self.key_name,
-                                    value,
-                                    FieldFormat::Default,
-                                    0,
-                                );
-                                Ok(())
-                            }
-
-                            fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error> {
-                                self.eb.add_value(
-                                    self.key_name,
-                                    value,
-                                    FieldFormat::SignedInt,
-                                    0,
-                                );
-                                Ok(())
-                            }
-                        }
+        #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+        {
+            let key_values = WithoutReservedKeys(record.key_values());
 
-                        struct KvVisitor<'a> {
-                            eb: &'a mut EventBuilder,
-                        }
-                        impl<'kvs> Visitor<'kvs> for KvVisitor<'_> {
-                            fn visit_pair(
-                                &mut self,
-                                key: log::kv::Key<'kvs>,
-                                value: log::kv::Value<'kvs>,
-                            ) -> Result<(), log::kv::Error> {
-                                let mut value_visitor = ValueVisitor {
-                                    key_name: key.as_str(),
-                                    eb: &mut self.eb,
-                                };
-                                let _ = value.visit(&mut value_visitor);
-
-                                Ok(())
-                            }
-                        }
+            #[cfg(feature = "kv_unstable_json")]
+            let as_json = exporter_config.json;
+            #[cfg(not(feature = "kv_unstable_json"))]
+            let as_json = false;
 
-                        let _ = record.key_values().visit(&mut KvVisitor { eb: &mut eb });
+            if as_json {
+                #[cfg(feature = "kv_unstable_json")]
+                {
+                    buffer.clear();
+                    let map = log::kv::source::as_map(key_values);
+                    if serde_json::to_writer(&mut *buffer, &map).is_ok() {
+                        eb.add_str("Keys / Values", &buffer[..], FieldFormat::Default, 0);
                     }
                 }
-
-                if let Some(module_path) = record.module_path() {
-                    eb.add_str("Module Path", module_path, FieldFormat::Default, 0);
+            } else {
+                struct ValueVisitor<'v, 'a> {
+                    key_name: &'v str,
+                    eb: &'a mut EventBuilder,
+                    buffer: &'a mut Vec<u8>,
                 }
+                impl<'v, 'a> Visit<'v> for ValueVisitor<'v, 'a> {
+                    fn visit_any(&mut self, value: log::kv::Value) -> Result<(), log::kv::Error> {
+                        use std::io::Write;
+
+                        self.buffer.clear();
+                        let _ = write!(self.buffer, "{}", value);
+                        self.eb
+                            .add_str(self.key_name, &self.buffer[..], FieldFormat::Default, 0);
+                        Ok(())
+                    }
 
-                if let Some(file) = record.file() {
-                    eb.add_str("File", file, FieldFormat::Default, 0);
+                    fn visit_bool(&mut self, value: bool) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_value(self.key_name, value as i32, FieldFormat::Boolean, 0);
+                        Ok(())
+                    }
 
-                    if let Some(line) = record.line() {
-                        eb.add_value("Line", line, FieldFormat::Default, 0);
+                    fn visit_borrowed_str(&mut self, value: &'v str) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_str(self.key_name, value, FieldFormat::Default, 0);
+                        Ok(())
                     }
-                }
 
-                let _ = eb.write(&es, None, None);
-            } else {
-                eb.reset(&event_name, 0);
-                eb.opcode(Opcode::Info);
+                    fn visit_str(&mut self, value: &str) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_str(self.key_name, value, FieldFormat::Default, 0);
+                        Ok(())
+                    }
 
-                let parta_field_count;
-                let span_id: Option<[u8; 16]>;
-                let trace_id: Option<[u8; 32]>;
-                #[cfg(any(feature = "spans"))]
-                {
-                    use std::io::Write;
-
-                    let active_span_id: [u8; 16];
-                    let active_trace_id: [u8; 32];
-
-                    (active_span_id, active_trace_id) =
-                        opentelemetry_api::trace::get_active_span(|span| {
-                            if span.span_context().span_id()
-                                != opentelemetry_api::trace::SpanId::INVALID
-                            {
-                                let trace_id = unsafe {
-                                    let mut trace_id = std::mem::MaybeUninit::<[u8; 32]>::uninit();
-                                    let mut cur = std::io::Cursor::new(
-                                        (&mut *trace_id.as_mut_ptr()).as_mut_slice(),
-                                    );
-                                    write!(&mut cur, "{:32x}", span.span_context().trace_id())
-                                        .expect("!write");
-                                    trace_id.assume_init()
-                                };
-
-                                let span_id = unsafe {
-                                    let mut span_id = std::mem::MaybeUninit::<[u8; 16]>::uninit();
-                                    let mut cur = std::io::Cursor::new(
-                                        (&mut *span_id.as_mut_ptr()).as_mut_slice(),
-                                    );
-                                    write!(&mut cur, "{:16x}", span.span_context().span_id())
-                                        .expect("!write");
-                                    span_id.assume_init()
-                                };
-
-                                (span_id, trace_id)
-                            } else {
-                                ([0; 16], [0; 32])
-                            }
-                        });
-
-                    parta_field_count = 2;
-                    span_id = Some(active_span_id);
-                    trace_id = Some(active_trace_id);
-                }
-                #[cfg(not(any(feature = "spans")))]
-                {
-                    parta_field_count = 1;
-                    span_id = None;
-                    trace_id = None;
-                }
+                    fn visit_char(&mut self, value: char) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_value(self.key_name, value as u8, FieldFormat::String8, 0);
+                        Ok(())
+                    }
 
-                eb.add_value("__csver__", 0x0401, FieldFormat::SignedInt, 0);
-                eb.add_struct("PartA", parta_field_count, 0);
-                {
-                    let time: String = chrono::DateTime::to_rfc3339(
-                        &chrono::DateTime::<chrono::Utc>::from(timestamp),
-                    );
-                    eb.add_str("time", time, FieldFormat::Default, 0);
-
-                    if trace_id.is_some() {
-                        eb.add_struct("ext_dt", 2, 0);
-                        {
-                            eb.add_str("traceId", &trace_id.unwrap(), FieldFormat::Default, 0);
-                            eb.add_str("spanId", &span_id.unwrap(), FieldFormat::Default, 0);
+                    fn visit_f64(&mut self, value: f64) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_value(self.key_name, value, FieldFormat::Float, 0);
+                        Ok(())
+                    }
+
+                    fn visit_i128(&mut self, value: i128) -> Result<(), log::kv::Error> {
+                        unsafe {
+                            self.eb.add_value_sequence(
+                                self.key_name,
+                                core::slice::from_raw_parts(
+                                    &value.to_le_bytes() as *const u8 as *const u64,
+                                    2,
+                                ),
+                                FieldFormat::HexInt,
+                                0,
+                            );
                         }
+                        Ok(())
+                    }
+
+                    fn visit_u128(&mut self, value: u128) -> Result<(), log::kv::Error> {
+                        unsafe {
+                            self.eb.add_value_sequence(
+                                self.key_name,
+                                core::slice::from_raw_parts(
+                                    &value.to_le_bytes() as *const u8 as *const u64,
+                                    2,
+                                ),
+                                FieldFormat::HexInt,
+                                0,
+                            );
+                        }
+                        Ok(())
+                    }
+
+                    fn visit_u64(&mut self, value: u64) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_value(self.key_name, value, FieldFormat::Default, 0);
+                        Ok(())
+                    }
+
+                    fn visit_i64(&mut self, value: i64) -> Result<(), log::kv::Error> {
+                        self.eb
+                            .add_value(self.key_name, value, FieldFormat::SignedInt, 0);
+                        Ok(())
                     }
                 }
 
-                eb.add_struct("PartB", 5, 0);
-                {
-                    eb.add_str("_typeName", "Log", FieldFormat::Default, 0);
-                    eb.add_str("name", event_name, FieldFormat::Default, 0);
-
-                    eb.add_str(
-                        "eventTime",
-                        &chrono::DateTime::to_rfc3339(&chrono::DateTime::<chrono::Utc>::from(
-                            timestamp,
-                        )),
-                        FieldFormat::Default,
-                        0,
-                    );
-
-                    eb.add_value(
-                        "severityNumber",
-                        record.level() as u8,
-                        FieldFormat::Default,
-                        0,
-                    );
-                    eb.add_str(
-                        "severityText",
-                        record.level().as_str(),
-                        FieldFormat::Default,
-                        0,
-                    );
+                struct KvVisitor<'a> {
+                    eb: &'a mut EventBuilder,
+                    buffer: &'a mut Vec<u8>,
+                }
+                impl<'kvs> Visitor<'kvs> for KvVisitor<'_> {
+                    fn visit_pair(
+                        &mut self,
+                        key: log::kv::Key<'kvs>,
+                        value: log::kv::Value<'kvs>,
+                    ) -> Result<(), log::kv::Error> {
+                        let mut value_visitor = ValueVisitor {
+                            key_name: key.as_str(),
+                            eb: self.eb,
+                            buffer: self.buffer,
+                        };
+                        let _ = value.visit(&mut value_visitor);
+
+                        Ok(())
+                    }
                 }
 
-                eb.add_struct("PartC", 1, 0);
-                {
-                    let payload = format!("{}", record.args());
-                    eb.add_str("Payload", payload, FieldFormat::Default, 0);
+                let _ = key_values.visit(&mut KvVisitor {
+                    eb: &mut *eb,
+                    buffer: &mut *buffer,
+                });
+            }
+        }
+
+        if field_set.module_path {
+            if let Some(module_path) = record.module_path() {
+                eb.add_str("Module Path", module_path, FieldFormat::Default, 0);
+            }
+        }
+
+        if field_set.file {
+            if let Some(file) = record.file() {
+                eb.add_str("File", file, FieldFormat::Default, 0);
+            }
+        }
+
+        if field_set.line {
+            if let Some(line) = record.line() {
+                eb.add_value("Line", line, FieldFormat::Default, 0);
+            }
+        }
+
+        if field_set.target {
+            eb.add_str("Target", record.target(), FieldFormat::Default, 0);
+        }
+
+        if field_set.level {
+            eb.add_str("Level", record.level().as_str(), FieldFormat::Default, 0);
+        }
+
+        if field_set.thread_id {
+            eb.add_value("Thread ID", current_thread_id(), FieldFormat::Pid, 0);
+        }
+
+        if field_set.thread_name {
+            with_thread_name(|name| {
+                if let Some(name) = name {
+                    eb.add_str("Thread Name", name, FieldFormat::Default, 0);
                 }
+            });
+        }
 
-                let _ = eb.write(&es, None, None);
+        if field_set.process_id {
+            eb.add_value("Process ID", std::process::id(), FieldFormat::Pid, 0);
+        }
+    } else {
+        eb.reset(options.name, options.tag as u16);
+        eb.opcode(Opcode::from_int(options.opcode));
+        eb.id_version(options.id, options.version);
+
+        let parta_field_count;
+        let span_id: Option<[u8; 16]>;
+        let trace_id: Option<[u8; 32]>;
+        #[cfg(feature = "spans")]
+        {
+            let (active_span_id, active_trace_id) = active_span_ids();
+
+            parta_field_count = 2;
+            span_id = Some(active_span_id);
+            trace_id = Some(active_trace_id);
+        }
+        #[cfg(not(feature = "spans"))]
+        {
+            parta_field_count = 1;
+            span_id = None;
+            trace_id = None;
+        }
+
+        let time = format_rfc3339(buffer, timestamp);
+
+        eb.add_value("__csver__", 0x0401, FieldFormat::SignedInt, 0);
+        eb.add_struct("PartA", parta_field_count, 0);
+        {
+            eb.add_str("time", time, FieldFormat::Default, 0);
+
+            if let (Some(trace_id), Some(span_id)) = (trace_id, span_id) {
+                eb.add_struct("ext_dt", 2, 0);
+                {
+                    eb.add_str("traceId", trace_id, FieldFormat::Default, 0);
+                    eb.add_str("spanId", span_id, FieldFormat::Default, 0);
+                }
             }
-        })
+        }
+
+        eb.add_struct("PartB", 5, 0);
+        {
+            eb.add_str("_typeName", "Log", FieldFormat::Default, 0);
+            eb.add_str("name", options.name, FieldFormat::Default, 0);
+
+            eb.add_str("eventTime", time, FieldFormat::Default, 0);
+
+            eb.add_value(
+                "severityNumber",
+                record.level() as u8,
+                FieldFormat::Default,
+                0,
+            );
+            eb.add_str(
+                "severityText",
+                record.level().as_str(),
+                FieldFormat::Default,
+                0,
+            );
+        }
+
+        eb.add_struct("PartC", 1, 0);
+        {
+            eb.add_str(
+                "Payload",
+                format_message(record.args(), buffer),
+                FieldFormat::Default,
+                0,
+            );
+        }
     }
 }
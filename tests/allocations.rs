@@ -0,0 +1,117 @@
+use log::info;
+use log_etw::__private::RecordEncoder;
+use log_etw::logger::{new_logger, FieldSet, TimestampFormat};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+// Counts are per thread, so tests running in parallel do not affect each other
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|count| count.get())
+}
+
+fn log_some_records() {
+    let answer = 42;
+    info!(target: "LogEtwAllocations", "Static message");
+    info!(target: "LogEtwAllocations", "Formatted message {}", answer);
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    info!(target: "LogEtwAllocations", key = 1, _event = "WithKeys"; "Message with keys");
+}
+
+// No session is listening, so this covers resolving the provider and checking whether the
+// event is enabled. Building the event itself is covered by the next test.
+#[test]
+fn disabled_records_do_not_allocate() {
+    new_logger("LogEtwAllocations").install();
+
+    // The first records create the provider and fill the per-thread caches
+    log_some_records();
+
+    let before = allocations();
+    for _ in 0..100 {
+        log_some_records();
+    }
+    assert_eq!(allocations() - before, 0);
+}
+
+fn encode_some_records(encoder: &RecordEncoder) {
+    let answer = 42;
+    encoder.encode(
+        &log::Record::builder()
+            .args(format_args!("Static message"))
+            .build(),
+    );
+    encoder.encode(
+        &log::Record::builder()
+            .args(format_args!("Formatted message {}", answer))
+            .module_path(Some(module_path!()))
+            .file(Some(file!()))
+            .line(Some(line!()))
+            .build(),
+    );
+    #[cfg(any(feature = "kv_unstable", feature = "kv_unstable_json"))]
+    {
+        let kvs: &[(&str, &dyn log::kv::ToValue)] = &[("key", &1), ("name", &"value")];
+        encoder.encode(
+            &log::Record::builder()
+                .args(format_args!("Message with keys"))
+                .key_values(&kvs)
+                .build(),
+        );
+    }
+}
+
+#[test]
+fn steady_state_encoding_does_not_allocate() {
+    let all_fields = FieldSet {
+        time: true,
+        payload: true,
+        module_path: true,
+        file: true,
+        line: true,
+        target: true,
+        level: true,
+        thread_id: true,
+        thread_name: true,
+        process_id: true,
+    };
+    let encoders = [
+        RecordEncoder::new(
+            new_logger("LogEtwEncoding")
+                .with_field_set(all_fields)
+                .with_timestamp_format(TimestampFormat::Rfc3339),
+        )
+        .unwrap(),
+        RecordEncoder::new(new_logger("LogEtwEncoding").with_common_schema_events()).unwrap(),
+    ];
+
+    for encoder in &encoders {
+        // The first records grow the builder and the scratch buffer
+        encode_some_records(encoder);
+
+        let before = allocations();
+        for _ in 0..100 {
+            encode_some_records(encoder);
+        }
+        assert_eq!(allocations() - before, 0);
+    }
+}
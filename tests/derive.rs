@@ -0,0 +1,110 @@
+#![cfg(feature = "derive")]
+
+use log_etw::typed_events::{EventField, EventWriter, FieldFormat};
+use log_etw::{log_event, LogEtwEvent};
+
+#[derive(LogEtwEvent)]
+#[etw(
+    provider = "LogEtwDeriveTest",
+    name = "RequestCompleted",
+    level = "warn",
+    keyword = 0x2,
+    opcode = "stop"
+)]
+struct RequestCompleted<'a> {
+    path: &'a str,
+    #[etw(format = "hex")]
+    status: u32,
+    #[etw(rename = "LatencyMs")]
+    latency_ms: f64,
+    cached: bool,
+    #[etw(skip)]
+    #[allow(dead_code)]
+    retries: Vec<String>,
+    tenant: Tenant,
+}
+
+struct Tenant(u64);
+
+impl EventField for Tenant {
+    fn add_field(&self, name: &str, format: FieldFormat, writer: &mut EventWriter) {
+        writer.add_u64(name, self.0, format);
+    }
+}
+
+#[derive(LogEtwEvent)]
+#[etw(provider = "LogEtwDeriveTest")]
+struct CacheEvicted;
+
+#[test]
+fn derived_metadata() {
+    assert_eq!(RequestCompleted::PROVIDER, "LogEtwDeriveTest");
+    assert_eq!(RequestCompleted::NAME, "RequestCompleted");
+    assert_eq!(RequestCompleted::LEVEL, log::Level::Warn);
+    assert_eq!(RequestCompleted::KEYWORD, 0x2);
+    assert_eq!(RequestCompleted::OPCODE, 2);
+
+    assert_eq!(CacheEvicted::NAME, "CacheEvicted");
+    assert_eq!(CacheEvicted::LEVEL, log::Level::Info);
+    assert_eq!(CacheEvicted::KEYWORD, 1);
+    assert_eq!(CacheEvicted::OPCODE, 0);
+}
+
+#[test]
+fn log_derived_events() {
+    let handle = log_etw::logger::new_logger("LogEtwDeriveDefault")
+        .try_install()
+        .unwrap();
+
+    let log_both = || {
+        log_event!(RequestCompleted {
+            path: "/index.html",
+            status: 200,
+            latency_ms: 1.5,
+            cached: true,
+            retries: Vec::new(),
+            tenant: Tenant(42),
+        });
+        log_event!(CacheEvicted);
+    };
+    let attempted = || {
+        handle
+            .stats()
+            .into_iter()
+            .find(|stats| stats.name == "LogEtwDeriveTest")
+            .unwrap()
+            .attempted
+    };
+
+    // Both events reach the shared provider, whether or not a session is listening
+    log_both();
+    let stats = handle
+        .stats()
+        .into_iter()
+        .find(|stats| stats.name == "LogEtwDeriveTest")
+        .unwrap();
+    assert_eq!(stats.attempted, 2);
+    assert_eq!(
+        stats.written + stats.disabled + stats.failed + stats.truncated,
+        2
+    );
+
+    // The installed logger's level filter applies to typed events too
+    handle
+        .reconfigure(
+            log_etw::logger::new_logger("LogEtwDeriveDefault")
+                .with_max_level(log::LevelFilter::Warn),
+        )
+        .unwrap();
+    log_both();
+    assert_eq!(attempted(), 3);
+
+    handle
+        .reconfigure(
+            log_etw::logger::new_logger("LogEtwDeriveDefault")
+                .with_max_level(log::LevelFilter::Off),
+        )
+        .unwrap();
+    log_both();
+    assert_eq!(attempted(), 3);
+}
@@ -0,0 +1,39 @@
+use log::{info, Level, LevelFilter, Metadata};
+
+fn enabled(target: &str) -> bool {
+    log::logger().enabled(
+        &Metadata::builder()
+            .target(target)
+            .level(Level::Error)
+            .build(),
+    )
+}
+
+#[test]
+fn shutdown_is_idempotent() {
+    let handle = log_etw::logger::new_logger("LogEtwShutdown")
+        .with_providers(["LogEtwShutdownOther"])
+        .try_install()
+        .unwrap();
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+
+    info!(target: "LogEtwShutdownOther", "Before shutdown");
+
+    handle.shutdown();
+    assert_eq!(log::max_level(), LevelFilter::Off);
+    assert!(!enabled("LogEtwShutdownOther"));
+    assert!(!enabled("LogEtwShutdownNew"));
+
+    // Dropped without creating or registering providers
+    log::logger().log(
+        &log::Record::builder()
+            .target("LogEtwShutdownNew")
+            .level(Level::Error)
+            .args(format_args!("After shutdown"))
+            .build(),
+    );
+
+    handle.shutdown();
+    log_etw::logger::shutdown();
+    handle.flush();
+}